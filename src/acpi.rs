@@ -0,0 +1,89 @@
+//! Minimal lookup of ACPI tables, read through the identity mapping before exit
+
+use alloc::vec::Vec;
+
+/// Length of the common header of system description tables
+const HEADER_LEN: u64 = 36;
+
+/// Read a value of type `T` at physical address `addr`
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    core::ptr::read_unaligned(addr as *const T)
+}
+
+/// Physical address and length of the XSDT, or of the RSDT if there is no XSDT
+pub fn root_table(rsdp_addr: u64) -> (u64, u32) {
+    unsafe {
+        let revision = read::<u8>(rsdp_addr + 15);
+        let xsdt_addr = if revision >= 2 {
+            read::<u64>(rsdp_addr + 24)
+        } else {
+            0
+        };
+        let addr = if xsdt_addr != 0 {
+            xsdt_addr
+        } else {
+            read::<u32>(rsdp_addr + 16) as u64
+        };
+        (addr, table_len(addr))
+    }
+}
+
+/// Physical address and length of the first table with `signature`
+/// referenced by the XSDT or RSDT at `root_addr`
+pub fn find_table(root_addr: u64, signature: &[u8; 4]) -> Option<(u64, u32)> {
+    unsafe {
+        // XSDT entries are 64-bit, RSDT entries 32-bit
+        let entry_size = if read::<[u8; 4]>(root_addr) == *b"XSDT" {
+            8
+        } else {
+            4
+        };
+        let entries = (table_len(root_addr) as u64).saturating_sub(HEADER_LEN) / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = root_addr + HEADER_LEN + i * entry_size;
+                match entry_size {
+                    8 => read::<u64>(entry),
+                    _ => read::<u32>(entry) as u64,
+                }
+            })
+            .find(|&addr| addr != 0 && read::<[u8; 4]>(addr) == *signature)
+            .map(|addr| (addr, table_len(addr)))
+    }
+}
+
+/// The local APIC IDs of the enabled processors in the MADT at `madt_addr`,
+/// from its local APIC and local x2APIC entries
+pub fn apic_ids(madt_addr: u64) -> Vec<u32> {
+    // the entries follow the local APIC address and flags
+    let end = madt_addr + unsafe { table_len(madt_addr) } as u64;
+    let mut entry = madt_addr + HEADER_LEN + 8;
+    let mut ids = Vec::new();
+    while entry + 2 <= end {
+        let (ty, len) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1)) };
+        if len < 2 || entry + len as u64 > end {
+            warn!("malformed MADT entry at {:#x}", entry);
+            break;
+        }
+        let (id, flags) = match (ty, len) {
+            // processor local APIC
+            (0, 8..) => unsafe { (read::<u8>(entry + 3) as u32, read::<u32>(entry + 4)) },
+            // processor local x2APIC
+            (9, 16..) => unsafe { (read::<u32>(entry + 4), read::<u32>(entry + 8)) },
+            _ => {
+                entry += len as u64;
+                continue;
+            }
+        };
+        if flags & 1 != 0 {
+            ids.push(id);
+        }
+        entry += len as u64;
+    }
+    ids
+}
+
+/// Length of the table at `addr`, from its header
+unsafe fn table_len(addr: u64) -> u32 {
+    read::<u32>(addr + 4)
+}
@@ -0,0 +1,1567 @@
+//! The boot sequence, from a parsed config to jumping to the kernel
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem::{size_of, size_of_val};
+use rboot::{
+    BootConfig, BootInfo, BootTime, GraphicInfo, MemoryRange, Module, PageTableSummary,
+    KERNEL_COMPRESSION_LZ4, KERNEL_COMPRESSION_NONE, PIXEL_FORMAT_BGR, PIXEL_FORMAT_RGB,
+    TIMEZONE_UNSPECIFIED,
+};
+use uefi::prelude::*;
+use uefi::proto::console::gop::PixelFormat;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::*;
+use uefi::table::cfg::{ACPI2_GUID, SMBIOS3_GUID, SMBIOS_GUID};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::*;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::*;
+use x86_64::{PhysAddr, VirtAddr};
+use xmas_elf::ElfFile;
+
+use crate::config::{BootInfoRegister, Config, DirectMap, KernelCompression, KernelFormat, Origin};
+use crate::fs::{self, Source};
+use crate::graphic;
+use crate::{acpi, crc32, elf, fat, kaslr, la57, logfile, lz4, page_table, smbios, tar, var};
+
+/// The kernel symbol holding the physical memory offset it was built for
+const PHYS_OFFSET_SYMBOL: &str = "__phys_offset";
+
+/// The page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+
+/// The memory type of a PAT entry for write-combining
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+/// The UEFI variable counting boots, used to select from `chain_next` kernels
+const BOOT_COUNT_VAR: &str = "RbootBootCount";
+
+/// The UEFI variable counting boots of `kernel_path` without success with `ab_boot`
+const AB_ATTEMPTS_VAR: &str = "BootAttempts";
+
+/// Boot the kernel given by `config`, which was loaded from the boot volume.
+///
+/// A bundle or boot image named by `config` may replace it.
+pub fn run(image: Handle, st: SystemTable<Boot>, config: Config<'static>) -> ! {
+    // before the first message, with the config from the boot volume
+    let cleared = config.quiet;
+    if config.quiet {
+        log::set_max_level(log::LevelFilter::Error);
+        let mut st = unsafe { st.unsafe_clone() };
+        graphic::clear_screen(st.stdout());
+    }
+    let bs = st.boot_services();
+    fs::set_open_retries(config.file_open_retries);
+    let source = if let Some(path) = config.bundle_path {
+        let buf = fs::load(bs, image, None, path, config.io_timeout);
+        let bundle =
+            tar::Archive::new(buf).unwrap_or_else(|e| panic!("malformed bundle {}: {}", path, e));
+        Some(Source::Bundle(bundle))
+    } else if let Some(path) = config.boot_image {
+        let buf = fs::load(bs, image, None, path, config.io_timeout);
+        let volume = fat::Volume::new(buf)
+            .unwrap_or_else(|e| panic!("malformed boot image {}: {}", path, e));
+        Some(Source::Image(volume))
+    } else {
+        None
+    };
+    let (mut config, config_path) = match &source {
+        Some(source) if source.contains(source.config_path()) => {
+            info!("using config from {}", source.config_path());
+            let buf: &'static [u8] = fs::load(bs, image, Some(source), source.config_path(), 0);
+            match Config::parse(buf) {
+                Ok(source_config) => {
+                    log_checksum(&source_config, source.config_path(), buf);
+                    (source_config, source.config_path())
+                }
+                Err(e) => {
+                    error!(
+                        "invalid config {}: {}, using {}",
+                        source.config_path(),
+                        e,
+                        crate::CONFIG_PATH
+                    );
+                    (config, crate::CONFIG_PATH)
+                }
+            }
+        }
+        _ => (config, crate::CONFIG_PATH),
+    };
+    config
+        .validate()
+        .unwrap_or_else(|e| panic!("invalid config: {}", e));
+    if config.quiet {
+        log::set_max_level(log::LevelFilter::Error);
+    }
+    // the config of a bundle or boot image may be quiet unlike the first one
+    if config.quiet && !cleared {
+        let mut st = unsafe { st.unsafe_clone() };
+        graphic::clear_screen(st.stdout());
+    }
+    fs::set_open_retries(config.file_open_retries);
+    if config.log_file.is_some() {
+        let mut st = unsafe { st.unsafe_clone() };
+        logfile::start(st.stdout());
+    }
+    info!("config: {:#x?}", config);
+
+    let mut cmdline = config.kernel_cmdline();
+    let options = load_options(bs, image);
+    if let Some(options) = &options {
+        info!("load options: {:?}", options);
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        cmdline.push_str(options);
+        config.set_origin("cmdline", Origin::LoadOptions);
+    }
+    let cmdline: &'static str = Box::leak(cmdline.into_boxed_str());
+    info!("cmdline: {:?}", cmdline);
+
+    let acpi2_addr = st
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == ACPI2_GUID)
+        .expect("failed to find ACPI 2 RSDP")
+        .address;
+    info!("acpi2: {:?}", acpi2_addr);
+    let (xsdt_addr, xsdt_len) = acpi::root_table(acpi2_addr as u64);
+    info!("xsdt: {:#x}, len: {:#x}", xsdt_addr, xsdt_len);
+    let (srat_addr, srat_len) = acpi::find_table(xsdt_addr, b"SRAT").unwrap_or((0, 0));
+    info!("srat: {:#x}, len: {:#x}", srat_addr, srat_len);
+    let apic_ids = match acpi::find_table(xsdt_addr, b"APIC") {
+        Some((madt_addr, _)) => acpi::apic_ids(madt_addr),
+        None => {
+            warn!("failed to find MADT");
+            Vec::new()
+        }
+    };
+    let cpu_count = (apic_ids.len() as u32).max(1);
+    info!("{} processors, APIC IDs: {:?}", cpu_count, apic_ids);
+
+    let find_table = |guid| {
+        st.config_table()
+            .iter()
+            .find(|entry| entry.guid == guid)
+            .map(|entry| entry.address as u64)
+    };
+    let smbios2_addr = find_table(SMBIOS_GUID);
+    let smbios3_addr = find_table(SMBIOS3_GUID);
+    info!("smbios: {:x?}, smbios3: {:x?}", smbios2_addr, smbios3_addr);
+    // firmware may only have the 64-bit entry point
+    let smbios_addr = smbios2_addr.or(smbios3_addr).unwrap_or_else(|| {
+        warn!("failed to find SMBIOS");
+        0
+    });
+    // prefer the 64-bit entry point
+    let smbios_entry_addr = smbios3_addr.or(smbios2_addr).unwrap_or(0);
+    let smbios_entry = match smbios_entry_addr {
+        0 => &[],
+        addr => smbios::entry_point(addr).unwrap_or_else(|| {
+            warn!("invalid SMBIOS entry point");
+            &[]
+        }),
+    };
+    info!("smbios entry point: {:x?}", smbios_entry);
+
+    let system_table_addr = st.as_ptr() as u64;
+    let image_addr = image.as_ptr() as u64;
+
+    let firmware_vendor: &'static str =
+        Box::leak(format!("{}", st.firmware_vendor()).into_boxed_str());
+    let firmware_revision = st.firmware_revision();
+    let firmware_revision =
+        (firmware_revision.major() as u32) << 16 | firmware_revision.minor() as u32;
+    info!("firmware: {} {:#x}", firmware_vendor, firmware_revision);
+    let boot_time = match st.runtime_services().get_time() {
+        Ok(time) => Some(BootTime {
+            year: time.year(),
+            month: time.month(),
+            day: time.day(),
+            hour: time.hour(),
+            minute: time.minute(),
+            second: time.second(),
+            nanosecond: time.nanosecond(),
+            timezone: time.time_zone().unwrap_or(TIMEZONE_UNSPECIFIED),
+            daylight: time.daylight().bits(),
+        }),
+        Err(e) => {
+            warn!("failed to get time: {:?}", e.status());
+            None
+        }
+    };
+    info!("boot time: {:?}", boot_time);
+
+    delay(bs, config.delay_before_load, "loading");
+    if !config.machines.is_empty() {
+        if let Some(index) = matching_machine(&config, smbios_entry) {
+            config.kernel_path = config.machines[index].kernel_path;
+            config.set_origin("kernel_path", Origin::Machine(index));
+        }
+    }
+    let (kernel_path, selected_by) = select_kernel(st.runtime_services(), &config);
+    if let Some(name) = selected_by {
+        config.set_origin("kernel_path", Origin::Variable(name));
+    }
+    log_config_origins(&config, config_path);
+    let mut kernel_slide = 0;
+    let kernel_compressed_size;
+    let kernel_decompressed_size;
+    let kernel_compression;
+    let elf = {
+        let mut loaded = fs::load_pages(bs, image, source.as_ref(), kernel_path, config.io_timeout);
+        log_checksum(&config, kernel_path, loaded.buf);
+        kernel_compressed_size = loaded.buf.len() as u64;
+        let lz4 = match config.kernel_compression {
+            KernelCompression::Auto => lz4::is_lz4(loaded.buf),
+            KernelCompression::None => false,
+            KernelCompression::Lz4 => true,
+        };
+        kernel_compression = if lz4 {
+            loaded = fs::decompress_lz4(bs, loaded);
+            KERNEL_COMPRESSION_LZ4
+        } else {
+            KERNEL_COMPRESSION_NONE
+        };
+        kernel_decompressed_size = loaded.buf.len() as u64;
+        let pe = match config.kernel_format {
+            KernelFormat::Auto => is_pe(loaded.buf),
+            KernelFormat::Elf => false,
+            KernelFormat::Pe => true,
+        };
+        if pe {
+            start_pe(&st, image, &config, loaded.buf);
+        }
+        if config.preserve_phys_alignment {
+            loaded = fs::align_elf(bs, loaded);
+        }
+        let buf = loaded.buf;
+        if config.kaslr_bits != 0 {
+            let elf = parse_kernel(kernel_path, buf);
+            kernel_slide = kaslr::choose_slide(&elf, config.kaslr_bits);
+            info!("kernel slide: {:#x}", kernel_slide);
+            if kernel_slide != 0 {
+                kaslr::relocate(buf, kernel_slide);
+            }
+        }
+        parse_kernel(kernel_path, buf)
+    };
+    unsafe {
+        ENTRY = (elf.header.pt2.entry_point() + kernel_slide) as usize;
+    }
+    if config.check_phys_offset {
+        let offset = elf::symbol_u64(&elf, PHYS_OFFSET_SYMBOL)
+            .expect("failed to find physical memory offset symbol in kernel");
+        if offset != config.physical_memory_offset {
+            panic!(
+                "kernel was built for physical_memory_offset {:#x}, but config has {:#x}",
+                offset, config.physical_memory_offset
+            );
+        }
+    }
+
+    let build_id: &'static [u8] = match elf::build_id(&elf) {
+        Some(id) => {
+            let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+            info!("kernel build id: {}", hex);
+            Box::leak(id.into())
+        }
+        None => &[],
+    };
+
+    let note = elf::graphic_note(&elf);
+    info!("graphic note: {:x?}", note);
+    let resolution = config.resolution.or_else(|| {
+        note.filter(|note| note.width != 0 && note.height != 0)
+            .map(|note| (note.width as usize, note.height as usize))
+    });
+    let pixel_format = note.and_then(|note| match note.format {
+        PIXEL_FORMAT_RGB => Some(PixelFormat::Rgb),
+        PIXEL_FORMAT_BGR => Some(PixelFormat::Bgr),
+        _ => None,
+    });
+    let graphic_info = graphic::init_graphic(bs, resolution, pixel_format);
+    let available_graphic_modes = graphic::available_modes(bs, config.max_graphic_modes);
+    info!("{} graphic modes", available_graphic_modes.len());
+    if config.fb_test {
+        match &graphic_info {
+            Some(graphic_info) => graphic::draw_test_pattern(graphic_info),
+            None => warn!("no framebuffer to test"),
+        }
+    }
+    let fb_backbuffer_addr = match &graphic_info {
+        Some(graphic_info) if config.fb_backbuffer => {
+            let pages = (graphic_info.fb_size as usize + 0xFFF) / 0x1000;
+            // reserved in the memory map, so that the kernel does not take it as free
+            let addr = bs
+                .allocate_pages(AllocateType::AnyPages, MemoryType::RESERVED, pages)
+                .expect("failed to allocate framebuffer back-buffer");
+            unsafe { core::ptr::write_bytes(addr as *mut u8, 0, pages * 0x1000) };
+            info!("framebuffer back-buffer at {:#x}", addr);
+            addr
+        }
+        None if config.fb_backbuffer => {
+            warn!("no framebuffer to allocate a back-buffer for");
+            0
+        }
+        _ => 0,
+    };
+
+    let (initramfs_addr, initramfs_size) = if let Some(path) = config.initramfs {
+        let placement = match (config.initramfs_addr, config.initramfs_max_addr) {
+            (0, 0) => AllocateType::AnyPages,
+            (0, max_addr) => AllocateType::MaxAddress(max_addr as _),
+            (addr, _) => AllocateType::Address(addr as _),
+        };
+        let result = fs::try_load_at(
+            bs,
+            image,
+            source.as_ref(),
+            path,
+            config.io_timeout,
+            placement,
+        )
+        .map(|loaded| loaded.buf);
+        match result {
+            Ok(buf) => {
+                log_checksum(&config, path, buf);
+                (buf.as_ptr() as u64, buf.len() as u64)
+            }
+            Err(e) if config.initramfs_optional => {
+                warn!(
+                    "failed to load initramfs {}: {}, booting without it",
+                    path, e
+                );
+                (0, 0)
+            }
+            Err(e) => panic!("failed to load {}: {}", path, e),
+        }
+    } else {
+        (0, 0)
+    };
+
+    let (symbols_addr, symbols_size) = match config.kernel_symbols_path {
+        Some(path) => {
+            let buf = fs::load(bs, image, source.as_ref(), path, config.io_timeout);
+            (buf.as_ptr() as u64, buf.len() as u64)
+        }
+        None => (0, 0),
+    };
+
+    let mut modules: Vec<Module> = config
+        .modules
+        .iter()
+        .map(|module| {
+            let buf = fs::load(bs, image, source.as_ref(), module.path, config.io_timeout);
+            Module {
+                addr: buf.as_ptr() as u64,
+                size: buf.len() as u64,
+                name: file_name(module.path),
+                cmdline: module.cmdline,
+            }
+        })
+        .collect();
+    let dir_modules = match config.modules_dir {
+        Some(dir_path) => {
+            let mut dir = fs::open_dir(bs, image, dir_path);
+            fs::read_dir(&mut dir)
+                .into_iter()
+                .map(|name| {
+                    let path = format!("{}\\{}", dir_path.trim_end_matches('\\'), name);
+                    let mut file = fs::open_file(bs, image, &path);
+                    let buf =
+                        fs::load_file(bs, &mut file, config.io_timeout, AllocateType::AnyPages)
+                            .unwrap_or_else(|e| panic!("failed to load {}: {}", path, e))
+                            .buf;
+                    Module {
+                        addr: buf.as_ptr() as u64,
+                        size: buf.len() as u64,
+                        name: Box::leak(name.into_boxed_str()),
+                        cmdline: "",
+                    }
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    modules.extend(dir_modules);
+    info!("modules: {:#x?}", modules);
+
+    let mmap_size = st.boot_services().memory_map_size();
+    let mmap_storage = Box::leak(vec![0; mmap_size.map_size * 2].into_boxed_slice());
+    // the final memory map can not have more descriptors than fit in its storage
+    let max_descriptors = mmap_storage.len() / mmap_size.entry_size;
+    let mmap_version = memory_descriptor_version(bs);
+    info!(
+        "memory descriptor size: {:#x}, version: {}",
+        mmap_size.entry_size, mmap_version
+    );
+    let mut mmap = st
+        .boot_services()
+        .memory_map(mmap_storage)
+        .expect("failed to get memory map")
+        .1
+        .collect::<Vec<_>>();
+    // sorted the same way as the final memory map, which can not be checked after exit
+    mmap.sort_unstable_by_key(|m| m.phys_start);
+    debug_assert!(
+        mmap.windows(2)
+            .all(|w| w[0].phys_start + w[0].page_count * 0x1000 <= w[1].phys_start),
+        "memory map has overlapping descriptors"
+    );
+    if let Some(graphic_info) = &graphic_info {
+        graphic::reserve_framebuffer(bs, &mmap, graphic_info);
+    }
+    let max_phys_addr = mmap
+        .iter()
+        .map(|m| m.phys_start + m.page_count * 0x1000)
+        .max()
+        .unwrap()
+        .max(0x1_0000_0000); // include IOAPIC MMIO area
+
+    // Boot services regions lie below `max_phys_addr`, so they are covered
+    // by the physical memory mapping, and added to it with `direct_map=minimal`.
+    let boot_services_ranges = if config.keep_boot_services {
+        mmap.iter()
+            .filter(|m| {
+                m.ty == MemoryType::BOOT_SERVICES_CODE || m.ty == MemoryType::BOOT_SERVICES_DATA
+            })
+            .map(|m| MemoryRange {
+                start: m.phys_start,
+                end: m.phys_start + m.page_count * 0x1000,
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    info!("boot services ranges: {:#x?}", boot_services_ranges);
+
+    for range in &config.force_usable {
+        info!(
+            "force_usable: reporting {:#x}..{:#x} as conventional memory",
+            range.start, range.end
+        );
+        let overlapping = mmap.iter().filter(|m| {
+            m.phys_start < range.end && range.start < m.phys_start + m.page_count * 0x1000
+        });
+        for m in overlapping.filter(|m| m.ty != MemoryType::CONVENTIONAL) {
+            let start = m.phys_start.max(range.start);
+            let end = (m.phys_start + m.page_count * 0x1000).min(range.end);
+            if m.ty == MemoryType::LOADER_CODE || m.ty == MemoryType::LOADER_DATA {
+                warn!(
+                    "force_usable: {:#x}..{:#x} is used by rboot, the kernel may overwrite it",
+                    start, end
+                );
+            }
+            info!("force_usable: {:#x}..{:#x} was {:?}", start, end, m.ty);
+        }
+    }
+    // descriptors added to the final memory map by `force_usable`
+    let spare_descriptors =
+        Box::leak(vec![*mmap[0]; config.force_usable.len() * 3].into_boxed_slice());
+
+    unsafe {
+        Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    if config.fresh_page_table {
+        // build the kernel mappings in a writable copy of the firmware page table
+        let (frame, flags) = Cr3::read();
+        let frame = page_table::clone_page_table(frame, &mut UEFIFrameAllocator(bs))
+            .expect("failed to clone page table");
+        unsafe {
+            Cr3::write(frame, flags);
+        }
+    }
+    let pcid_supported = config.pcid && cpu_has_pcid();
+    if config.pcid && !pcid_supported {
+        warn!("PCID is not supported by the CPU");
+    }
+    let (kernel_flags, pcid_cr4_flags) = pcid_flags(config.pcid, pcid_supported);
+    let mut page_table = current_page_table();
+    if !config.fresh_page_table {
+        // root page table is readonly
+        // disable write protect
+        unsafe {
+            Cr0::update(|f| f.remove(Cr0Flags::WRITE_PROTECT));
+        }
+    }
+    let kernel_tables = page_table::map_elf(
+        &elf,
+        kernel_slide,
+        config.bss_init(),
+        kernel_flags,
+        config.protect_null,
+        &mut page_table,
+        &mut UEFIFrameAllocator(bs),
+    )
+    .unwrap_or_else(|e| panic!("failed to map ELF: {}", e));
+    let kernel_phys_range = MemoryRange {
+        start: elf.input.as_ptr() as u64,
+        end: elf.input.as_ptr() as u64 + elf.input.len() as u64,
+    };
+    if config.identity_map_kernel {
+        let tables = page_table::identity_map(
+            kernel_phys_range,
+            kernel_flags,
+            &mut page_table,
+            &mut UEFIFrameAllocator(bs),
+        )
+        .expect("failed to identity map kernel");
+        info!("identity mapped kernel: {:#x?}", tables);
+    }
+    let ap_trampoline_addr = if config.ap_trampoline {
+        let frame = UEFIFrameAllocator(bs)
+            .allocate_low_frame()
+            .expect("no free memory below 1MiB for the AP trampoline");
+        let range = MemoryRange {
+            start: frame.start_address().as_u64(),
+            end: frame.start_address().as_u64() + 0x1000,
+        };
+        check_null_page(&config, "AP trampoline", range.start, range.end);
+        page_table::identity_map(
+            range,
+            kernel_flags,
+            &mut page_table,
+            &mut UEFIFrameAllocator(bs),
+        )
+        .expect("failed to identity map AP trampoline");
+        info!("AP trampoline at {:#x}", range.start);
+        range.start
+    } else {
+        0
+    };
+    let stack_address = if config.kernel_stack_auto {
+        // leave unmapped pages, so that overflowing the image faults
+        let image_end = x86_64::align_up(elf::image_end(&elf) + kernel_slide, 0x1000);
+        let addr = image_end + config.kernel_stack_gap * 0x1000;
+        let stack_end = addr + config.kernel_stack_size * 0x1000;
+        if VirtAddr::try_new(stack_end - 1).is_err() || stack_end < image_end {
+            panic!("no room for the kernel stack above the kernel image");
+        }
+        info!("placing kernel stack at {:#x}", addr);
+        addr
+    } else {
+        config.kernel_stack_address
+    };
+    let lazy_stack = config.lazy_stack && {
+        let allowed = elf::lazy_stack_note(&elf);
+        if !allowed {
+            warn!("the kernel has no lazy stack note, mapping the whole stack");
+        }
+        allowed
+    };
+    let stack_mapped_pages = if lazy_stack {
+        config.lazy_stack_pages.min(config.kernel_stack_size)
+    } else {
+        config.kernel_stack_size
+    };
+    let stack_tables = page_table::map_stack(
+        stack_address,
+        config.kernel_stack_size,
+        stack_mapped_pages,
+        kernel_flags,
+        config.protect_null,
+        &mut page_table,
+        &mut UEFIFrameAllocator(bs),
+    )
+    .unwrap_or_else(|e| panic!("failed to map stack: {}", e));
+    let direct_map_ranges = match config.direct_map {
+        DirectMap::Full => vec![MemoryRange {
+            start: 0,
+            end: max_phys_addr,
+        }],
+        DirectMap::Minimal => {
+            let mut ranges = essential_ranges(&mmap, graphic_info.as_ref());
+            ranges.extend_from_slice(&boot_services_ranges);
+            if let Some(graphic_info) = graphic_info.as_ref().filter(|_| fb_backbuffer_addr != 0) {
+                // not loader data, so not among the essential ranges
+                ranges.push(MemoryRange {
+                    start: fb_backbuffer_addr,
+                    end: fb_backbuffer_addr + graphic_info.fb_size,
+                });
+            }
+            if config.direct_map_window != 0 {
+                ranges.push(MemoryRange {
+                    start: 0,
+                    end: config.direct_map_window,
+                });
+            }
+            ranges
+        }
+    };
+    check_stack_overlap(
+        config.physical_memory_offset,
+        &direct_map_ranges,
+        stack_address,
+        stack_address + config.kernel_stack_size * 0x1000,
+    );
+    let (direct_map_ranges, mut direct_map_tables) = page_table::map_physical_memory(
+        config.physical_memory_offset,
+        &direct_map_ranges,
+        config.direct_map_exec,
+        config.direct_map_clamp,
+        config.protect_null,
+        &mut page_table,
+        &mut UEFIFrameAllocator(bs),
+    )
+    .unwrap_or_else(|e| panic!("failed to map physical memory: {}", e));
+    info!("direct map ranges: {:#x?}", direct_map_ranges);
+    let fb_write_combining = graphic_info.is_some() && cpu_has_pat();
+    if let Some(graphic_info) = &graphic_info {
+        let start = config
+            .physical_memory_offset
+            .wrapping_add(graphic_info.fb_addr);
+        let end = start.saturating_add(graphic_info.fb_size);
+        // so that the flags below do not apply to memory next to it
+        direct_map_tables.table_frames +=
+            page_table::split_huge_pages(start, end, &mut page_table, &mut UEFIFrameAllocator(bs))
+                .unwrap_or_else(|e| panic!("failed to split framebuffer pages: {:?}", e));
+        // even with `direct_map_exec`, as it is never code
+        page_table::set_no_execute(start, end, &mut page_table);
+        if fb_write_combining {
+            page_table::set_write_combining(start, end, &mut page_table);
+        } else {
+            warn!("no PAT, the framebuffer is not mapped write-combining");
+        }
+    }
+    let direct_map_max = direct_map_ranges.last().map_or(0, |range| range.end);
+    // what the kernel touches first must be mapped
+    let stacktop = stack_address + config.kernel_stack_size * 0x1000;
+    check_entry(&config, &page_table, unsafe { ENTRY } as u64);
+    match page_table::translate_addr(&page_table, VirtAddr::new(stacktop - 8)) {
+        Some(phys) => info!(
+            "kernel stack top {:#x} is mapped to {:#x}",
+            stacktop - 8,
+            phys
+        ),
+        None => panic!("kernel stack top {:#x} is not mapped", stacktop - 8),
+    }
+    if config.stack_guard {
+        check_stack_guard(&page_table, stack_address);
+    }
+    let page_tables = PageTableSummary {
+        kernel: kernel_tables,
+        stack: stack_tables,
+        direct_map: direct_map_tables,
+    };
+    info!("page tables: {:#x?}", page_tables);
+    if config.clear_access_bits {
+        // rboot accesses the kernel only through its frames, so they stay clear until the jump
+        let load_segments = elf
+            .program_iter()
+            .filter(|segment| segment.get_type() == Ok(xmas_elf::program::Type::Load));
+        for segment in load_segments {
+            let start = segment.virtual_addr() + kernel_slide;
+            page_table::clear_access_bits(start, start + segment.mem_size(), &mut page_table);
+        }
+        page_table::clear_access_bits(stack_address, stacktop, &mut page_table);
+    }
+    if !config.fresh_page_table {
+        // recover write protect
+        unsafe {
+            Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+        }
+    }
+
+    if config.kernel_magic != 0 {
+        // read from the physical backing of the mapped kernel
+        let magic = elf::magic(&elf);
+        if magic != Some(config.kernel_magic) {
+            panic!(
+                "kernel magic mismatch: expected {:#x}, found {:#x?}",
+                config.kernel_magic, magic
+            );
+        }
+    }
+
+    let p5_frame = if config.five_level_paging && config.defer_exit_boot_services {
+        // the firmware can not run with 5-level paging
+        warn!("5-level paging is not supported with defer_exit_boot_services");
+        None
+    } else if config.five_level_paging && la57::supported() {
+        Some(la57::build_table(bs))
+    } else {
+        None
+    };
+
+    if config.dump_memory_map {
+        let (_key, descs) = bs
+            .memory_map(mmap_storage)
+            .expect("failed to get memory map");
+        info!("memory map before exit:");
+        for desc in descs {
+            info!(
+                "{:?} {:#x}..{:#x} {} pages {:?}",
+                desc.ty,
+                desc.phys_start,
+                desc.phys_start + desc.page_count * 0x1000,
+                desc.page_count,
+                desc.att
+            );
+        }
+    }
+
+    if config.cr0_set | config.cr0_clear | config.cr4_set | config.cr4_clear != 0
+        || config.efer_set | config.efer_clear != 0
+    {
+        // can not be logged after they are applied
+        info!(
+            "control registers before overrides: cr0 {:#x}, cr4 {:#x}, efer {:#x}",
+            Cr0::read_raw(),
+            Cr4::read_raw(),
+            Efer::read_raw()
+        );
+        info!(
+            "control registers after overrides: cr0 {:#x}, cr4 {:#x}, efer {:#x} (without pcid/five_level_paging)",
+            (Cr0::read_raw() | config.cr0_set) & !config.cr0_clear,
+            (Cr4::read_raw() | config.cr4_set) & !config.cr4_clear,
+            (Efer::read_raw() | config.efer_set) & !config.efer_clear
+        );
+    }
+
+    // allocate before exit, pushing to it must not reallocate
+    let mut memory_map: Vec<&'static MemoryDescriptor> =
+        Vec::with_capacity(max_descriptors + spare_descriptors.len());
+    let boot_config = if config.pass_config {
+        Some(boot_config(
+            &config,
+            config_path,
+            kernel_path,
+            stack_address,
+        ))
+    } else {
+        None
+    };
+    // what the kernel reaches through `BootInfo`, by reference or at
+    // `physical_memory_offset` for physical addresses
+    let phys = |addr: u64| config.physical_memory_offset.wrapping_add(addr);
+    let memory_map_size = memory_map.capacity() * size_of::<&MemoryDescriptor>();
+    // `bootinfo` is built on the stack of `run`, next to this
+    let stack_marker = 0u8;
+    let mut handoff = vec![
+        ("boot info", &stack_marker as *const u8 as u64, 1),
+        (
+            "memory_map",
+            memory_map.as_ptr() as u64,
+            memory_map_size as u64,
+        ),
+        slice_of("memory_map descriptors", mmap_storage),
+        slice_of("memory_map spare descriptors", spare_descriptors),
+        slice_of("smbios_entry", smbios_entry),
+        slice_of("cmdline", cmdline.as_bytes()),
+        slice_of("firmware_vendor", firmware_vendor.as_bytes()),
+        slice_of("build_id", build_id),
+        slice_of("apic_ids", &apic_ids),
+        slice_of("available_graphic_modes", &available_graphic_modes),
+        slice_of("modules", &modules),
+        slice_of("boot_services_ranges", &boot_services_ranges),
+        slice_of("direct_map_ranges", &direct_map_ranges),
+        // the RSDP of ACPI 2 is 36 bytes, the system table 120 bytes
+        ("acpi2_rsdp_addr", phys(acpi2_addr as u64), 36),
+        ("xsdt_addr", phys(xsdt_addr), xsdt_len as u64),
+        ("srat_addr", phys(srat_addr), srat_len as u64),
+        // the entry point `smbios_entry` was copied from
+        (
+            "smbios entry point",
+            phys(smbios_entry_addr),
+            smbios_entry.len() as u64,
+        ),
+        ("system_table_addr", phys(system_table_addr), 120),
+        ("initramfs_addr", phys(initramfs_addr), initramfs_size),
+        ("symbols_addr", phys(symbols_addr), symbols_size),
+        // identity mapped
+        (
+            "ap_trampoline_addr",
+            ap_trampoline_addr,
+            if ap_trampoline_addr != 0 { 0x1000 } else { 0 },
+        ),
+    ];
+    if let Some(graphic_info) = &graphic_info {
+        handoff.push(("fb_addr", phys(graphic_info.fb_addr), graphic_info.fb_size));
+        if fb_backbuffer_addr != 0 {
+            handoff.push((
+                "fb_backbuffer_addr",
+                phys(fb_backbuffer_addr),
+                graphic_info.fb_size,
+            ));
+        }
+    }
+    for module in &modules {
+        handoff.push(("module addr", phys(module.addr), module.size));
+        handoff.push(slice_of("module name", module.name.as_bytes()));
+        handoff.push(slice_of("module cmdline", module.cmdline.as_bytes()));
+    }
+    if let Some(boot_config) = &boot_config {
+        let paths = [
+            boot_config.config_path,
+            boot_config.kernel_path,
+            boot_config.kernel_symbols_path,
+            boot_config.initramfs,
+            boot_config.modules_dir,
+            boot_config.bundle_path,
+            boot_config.boot_image,
+        ];
+        for path in paths {
+            handoff.push(slice_of("config path", path.as_bytes()));
+        }
+    }
+    check_handoff(&config, &page_table, &handoff);
+
+    delay(bs, config.delay_before_exit, "exit");
+    graphic::keep_mode();
+    if config.defer_exit_boot_services {
+        info!("leaving boot services to the kernel");
+    } else {
+        info!("exit boot services");
+    }
+    if let Some(path) = config.log_file {
+        write_log_file(&st, image, path);
+    }
+    // after the last message on the console
+    let early_console = match &graphic_info {
+        Some(_) if config.early_console => {
+            let mut st = unsafe { st.unsafe_clone() };
+            graphic::early_console(st.stdout())
+        }
+        _ => None,
+    };
+    if config.defer_exit_boot_services {
+        let (_key, descs) = bs
+            .memory_map(mmap_storage)
+            .expect("failed to get memory map");
+        memory_map.extend(descs);
+    } else {
+        let (_rt, mmap_iter) = st
+            .exit_boot_services(image, mmap_storage)
+            .expect("Failed to exit boot services");
+        // NOTE: alloc & log can no longer be used
+
+        for desc in mmap_iter {
+            memory_map.push(desc);
+        }
+    }
+    // in place, since alloc can no longer be used
+    memory_map.sort_unstable_by_key(|m| m.phys_start);
+    force_usable(&mut memory_map, &config.force_usable, spare_descriptors);
+    // as passed to the kernel, including the descriptors cut by `force_usable`
+    debug_assert!(
+        memory_map
+            .windows(2)
+            .all(|w| w[0].phys_start <= w[1].phys_start),
+        "final memory map is not sorted"
+    );
+    debug_assert!(
+        memory_map
+            .windows(2)
+            .all(|w| w[0].phys_start + w[0].page_count * 0x1000 <= w[1].phys_start),
+        "final memory map has overlapping descriptors"
+    );
+
+    if let Some(frame) = p5_frame {
+        unsafe {
+            la57::switch(frame);
+        }
+    }
+    if !pcid_cr4_flags.is_empty() {
+        unsafe {
+            // Setting CR4.PCIDE raises #GP unless CR3[11:0] is 0, as those bits
+            // become the current PCID. The firmware may have left PWT/PCD there.
+            Cr3::write(Cr3::read().0, Cr3Flags::empty());
+            Cr4::update(|f| f.insert(pcid_cr4_flags));
+        }
+    }
+    if fb_write_combining {
+        unsafe {
+            program_pat_write_combining();
+        }
+    }
+    unsafe {
+        // The page table was changed while in use,
+        // so stale translations of remapped addresses may still be cached.
+        flush_tlb();
+        override_control_registers(&config);
+    }
+
+    // construct BootInfo
+    let bootinfo = BootInfo {
+        memory_map,
+        memory_descriptor_size: mmap_size.entry_size as u64,
+        memory_descriptor_version: mmap_version,
+        physical_memory_offset: config.physical_memory_offset,
+        graphic_info,
+        available_graphic_modes,
+        fb_backbuffer_addr,
+        early_console,
+        acpi2_rsdp_addr: acpi2_addr as u64,
+        smbios_addr,
+        smbios_entry,
+        initramfs_addr,
+        initramfs_size,
+        symbols_addr,
+        symbols_size,
+        cmdline,
+        boot_services_ranges,
+        system_table_addr,
+        firmware_vendor,
+        firmware_revision,
+        boot_time,
+        xsdt_addr,
+        xsdt_len,
+        srat_addr,
+        srat_len,
+        cpu_count,
+        apic_ids,
+        ap_trampoline_addr,
+        modules,
+        kernel_slide,
+        kernel_compression,
+        kernel_compressed_size,
+        kernel_decompressed_size,
+        build_id,
+        kernel_phys_range,
+        stack_bottom: stack_address,
+        stack_mapped_bottom: stack_address
+            + (config.kernel_stack_size - stack_mapped_pages) * 0x1000,
+        direct_map_ranges,
+        direct_map_max,
+        page_tables,
+        config: boot_config,
+        image_handle: if config.defer_exit_boot_services {
+            image_addr
+        } else {
+            0
+        },
+    };
+    unsafe {
+        let (rdi, rsi) = match config.bootinfo_register {
+            BootInfoRegister::Rdi => (&bootinfo as *const _ as u64, config.boot_magic),
+            BootInfoRegister::Rsi => (config.boot_magic, &bootinfo as *const _ as u64),
+        };
+        jump_to_entry(rdi, rsi, stacktop);
+    }
+}
+
+/// Select `kernel_path`, until it failed to set `ab_success_var` in
+/// `ab_max_attempts` boots counted by `AB_ATTEMPTS_VAR`,
+/// and `fallback_kernel_path` from then on.
+///
+/// Installing a new kernel must reset both variables to 0. If the attempt can
+/// not be counted, the selected kernel is booted anyway.
+/// Return the kernel, and `AB_ATTEMPTS_VAR` if it selected the fallback kernel.
+fn select_ab_kernel<'a>(
+    rt: &RuntimeServices,
+    config: &Config<'a>,
+) -> (&'a str, Option<&'static str>) {
+    if var::read_u32(rt, config.ab_success_var).unwrap_or(0) != 0 {
+        info!("kernel marked as booted successfully");
+        return (config.kernel_path, None);
+    }
+    let attempts = var::read_u32(rt, AB_ATTEMPTS_VAR).unwrap_or(0);
+    if attempts >= config.ab_max_attempts {
+        warn!(
+            "kernel failed to boot in {} attempts, booting fallback kernel",
+            attempts
+        );
+        return (config.fallback_kernel_path.unwrap(), Some(AB_ATTEMPTS_VAR));
+    }
+    if let Err(e) = var::write_u32(rt, AB_ATTEMPTS_VAR, attempts + 1) {
+        // better than not booting at all, though a failing kernel is retried forever
+        warn!(
+            "failed to count the boot attempt in {}: {:?}",
+            AB_ATTEMPTS_VAR,
+            e.status()
+        );
+    }
+    info!(
+        "boot attempt {} of {}",
+        attempts + 1,
+        config.ab_max_attempts
+    );
+    (config.kernel_path, None)
+}
+
+/// Log the CRC-32 of the file at `path` loaded to `data`, if `log_checksums` is set
+pub(crate) fn log_checksum(config: &Config, path: &str, data: &[u8]) {
+    if config.log_checksums {
+        info!("crc32 of {}: {:08x}", path, crc32::crc32(data));
+    }
+}
+
+/// Check that the kernel page table maps the memory the kernel reaches through
+/// `BootInfo`, given as field name, virtual address and size. Log each field
+/// which is not mapped, and refuse to boot with `strict`.
+fn check_handoff(config: &Config, page_table: &impl Translate, handoff: &[(&str, u64, u64)]) {
+    let mut unmapped = 0;
+    for &(field, addr, size) in handoff.iter().filter(|&&(_, _, size)| size != 0) {
+        let end = addr.saturating_add(size);
+        let mapped = (x86_64::align_down(addr, 0x1000)..end)
+            .step_by(0x1000)
+            .all(|page| {
+                matches!(VirtAddr::try_new(page), Ok(page)
+                    if page_table::translate_addr(page_table, page).is_some())
+            });
+        if !mapped {
+            warn!(
+                "{} at {:#x}..{:#x} is not mapped for the kernel",
+                field, addr, end
+            );
+            unmapped += 1;
+        }
+    }
+    if unmapped != 0 && config.strict {
+        panic!("{} boot info fields point to unmapped memory", unmapped);
+    }
+}
+
+/// Check that the kernel entry `entry` is mapped executable, rather than jumping
+/// into garbage after a bad ELF header, and warn if it is not in the higher half,
+/// which `strict` refuses.
+///
+/// x86 has no alignment requirement for code, so any address within a page will do.
+fn check_entry(config: &Config, page_table: &impl Translate, entry: u64) {
+    if entry == 0 {
+        panic!("kernel entry is 0");
+    }
+    let virt = VirtAddr::try_new(entry)
+        .unwrap_or_else(|_| panic!("kernel entry {:#x} is not canonical", entry));
+    let (phys, flags) = page_table::translate_flags(page_table, virt)
+        .unwrap_or_else(|| panic!("kernel entry {:#x} is not mapped", entry));
+    // rboot sets no-execute only on the pages, not on the tables above them
+    if flags.contains(PageTableFlags::NO_EXECUTE) {
+        panic!("kernel entry {:#x} is in a no-execute page", entry);
+    }
+    info!("kernel entry {:#x} is mapped to {:#x}", entry, phys);
+    if entry >> 63 == 0 {
+        warn!("kernel entry {:#x} is not in the higher half", entry);
+        if config.strict {
+            panic!("kernel entry {:#x} is not in the higher half", entry);
+        }
+    }
+}
+
+/// The field name, address and size of `slice`, for `check_handoff`
+fn slice_of<'a, T>(field: &'a str, slice: &[T]) -> (&'a str, u64, u64) {
+    (field, slice.as_ptr() as u64, size_of_val(slice) as u64)
+}
+
+/// Parse the kernel ELF loaded from `path` to `buf`, and refuse one which rboot
+/// can not boot, before mapping or relocating it
+fn parse_kernel<'a>(path: &str, buf: &'a [u8]) -> ElfFile<'a> {
+    let elf = ElfFile::new(buf).unwrap_or_else(|e| panic!("failed to parse ELF {}: {}", path, e));
+    elf::validate_elf(&elf).unwrap_or_else(|e| panic!("invalid kernel {}: {}", path, e));
+    elf
+}
+
+/// Whether `buf` is a PE/COFF image, whose MZ header points to a PE signature
+fn is_pe(buf: &[u8]) -> bool {
+    let pe_offset = match buf.get(0x3C..0x40) {
+        Some(b) if buf.starts_with(b"MZ") => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        _ => return false,
+    };
+    buf.get(pe_offset..pe_offset + 4) == Some(b"PE\0\0")
+}
+
+/// Start the PE/COFF kernel in `buf` as an EFI application with `LoadImage`
+/// and `StartImage`, after setting the graphic mode. Nothing else of the boot
+/// applies, the kernel gets no `BootInfo`.
+fn start_pe(st: &SystemTable<Boot>, image: Handle, config: &Config, buf: &[u8]) -> ! {
+    let bs = st.boot_services();
+    graphic::init_graphic(bs, config.resolution, None);
+    let kernel = bs
+        .load_image(
+            image,
+            LoadImageSource::FromBuffer {
+                buffer: buf,
+                file_path: None,
+            },
+        )
+        .unwrap_or_else(|e| panic!("failed to load PE kernel: {:?}", e.status()));
+    info!("starting PE kernel");
+    graphic::keep_mode();
+    if let Some(path) = config.log_file {
+        write_log_file(st, image, path);
+    }
+    let status = match bs.start_image(kernel) {
+        Ok(()) => Status::SUCCESS,
+        Err(e) => e.status(),
+    };
+    panic!("PE kernel returned: {:?}", status);
+}
+
+/// Stop capturing the console output, and write what was captured to `path`
+fn write_log_file(st: &SystemTable<Boot>, image: Handle, path: &str) {
+    let mut st = unsafe { st.unsafe_clone() };
+    let log = logfile::stop(st.stdout());
+    if let Err(status) = fs::write_file(st.boot_services(), image, path, &log) {
+        warn!("failed to write log to {}: {:?}", path, status);
+    }
+}
+
+/// Refuse to map `[start, end)` for `what` if it covers the null page,
+/// unless `protect_null` is off
+fn check_null_page(config: &Config, what: &str, start: u64, end: u64) {
+    if config.protect_null && start < 0x1000 && start < end {
+        panic!(
+            "{} at {:#x}..{:#x} would map the null page, which protect_null forbids",
+            what, start, end
+        );
+    }
+}
+
+/// Refuse a kernel stack at `[start, end)` which overlaps the physical memory
+/// `ranges` as mapped at `offset`, which would fail to map or corrupt the stack
+fn check_stack_overlap(offset: u64, ranges: &[MemoryRange], start: u64, end: u64) {
+    // mapped from 2MiB below the start, may end past 2^64 for a bad offset
+    let overlapping = ranges
+        .iter()
+        .filter(|range| range.start < range.end)
+        .map(|range| {
+            let map_start = x86_64::align_down(range.start, 0x20_0000) as u128 + offset as u128;
+            let map_end = x86_64::align_up(range.end, 0x1000) as u128 + offset as u128;
+            (map_start, map_end)
+        })
+        .find(|&(map_start, map_end)| (start as u128) < map_end && map_start < end as u128);
+    if let Some((map_start, map_end)) = overlapping {
+        panic!(
+            "kernel stack at {:#x}..{:#x} overlaps physical memory mapped at {:#x}..{:#x}",
+            start, end, map_start, map_end
+        );
+    }
+}
+
+/// Refuse a kernel stack at `stack_address` whose guard page below is mapped,
+/// which would let a stack overflow corrupt the memory mapped there
+fn check_stack_guard(page_table: &impl Translate, stack_address: u64) {
+    let guard = match stack_address.checked_sub(0x1000).map(VirtAddr::try_new) {
+        Some(Ok(guard)) => guard,
+        // nothing can be mapped there
+        _ => return,
+    };
+    if let Some(phys) = page_table::translate_addr(page_table, guard) {
+        panic!(
+            "stack guard page {:#x} below the kernel stack is mapped to {:#x}",
+            guard.as_u64(),
+            phys
+        );
+    }
+    info!("stack guard page at {:#x}", guard.as_u64());
+}
+
+/// The index of the first `machine.<index>` entry matching the SMBIOS system
+/// information, or none to boot `kernel_path`
+fn matching_machine(config: &Config, smbios_entry: &[u8]) -> Option<usize> {
+    let info = match smbios::system_info(smbios_entry) {
+        Some(info) => info,
+        None => {
+            warn!("no SMBIOS system information, booting kernel_path");
+            return None;
+        }
+    };
+    info!(
+        "machine: {:?} {:?}, serial {:?}",
+        info.manufacturer, info.product, info.serial
+    );
+    let matching = config.machines.iter().position(|machine| {
+        (machine.product.is_empty() || machine.product == info.product)
+            && (machine.serial.is_empty() || machine.serial == info.serial)
+    });
+    match matching {
+        Some(index) => info!("matches machine.{}", index),
+        None => info!("no machine entry matches, booting kernel_path"),
+    }
+    matching
+}
+
+/// Log where each config value came from, the rest are built-in defaults
+fn log_config_origins(config: &Config, path: &str) {
+    info!("config origins (others are defaults, the last of a key is in effect):");
+    for (key, origin) in config.origins() {
+        match origin {
+            Origin::Build => info!("  {}: RBOOT_DEFAULT_* at build time", key),
+            Origin::Line(number) => info!("  {}: {} line {}", key, path, number),
+            Origin::LoadOptions => info!("  {}: appended load options", key),
+            Origin::Variable(name) => info!("  {}: selected by UEFI variable {}", key, name),
+            Origin::Machine(index) => {
+                info!("  {}: machine.{} matching SMBIOS", key, index)
+            }
+        }
+    }
+}
+
+/// The config for `BootInfo::config`, with the values rboot booted with
+fn boot_config(
+    config: &Config<'static>,
+    config_path: &'static str,
+    kernel_path: &'static str,
+    stack_address: u64,
+) -> BootConfig {
+    let (width, height) = config.resolution.unwrap_or((0, 0));
+    BootConfig {
+        config_path,
+        kernel_path,
+        kernel_symbols_path: config.kernel_symbols_path.unwrap_or(""),
+        initramfs: config.initramfs.unwrap_or(""),
+        modules_dir: config.modules_dir.unwrap_or(""),
+        bundle_path: config.bundle_path.unwrap_or(""),
+        boot_image: config.boot_image.unwrap_or(""),
+        kernel_stack_address: stack_address,
+        kernel_stack_size: config.kernel_stack_size,
+        lazy_stack: config.lazy_stack,
+        stack_guard: config.stack_guard,
+        physical_memory_offset: config.physical_memory_offset,
+        direct_map_minimal: config.direct_map == DirectMap::Minimal,
+        direct_map_window: config.direct_map_window,
+        direct_map_exec: config.direct_map_exec,
+        resolution: (width as u32, height as u32),
+        initramfs_addr: config.initramfs_addr,
+        initramfs_max_addr: config.initramfs_max_addr,
+        zero_bss: config.zero_bss,
+        bss_fill: config.bss_fill,
+        preserve_phys_alignment: config.preserve_phys_alignment,
+        fresh_page_table: config.fresh_page_table,
+        keep_boot_services: config.keep_boot_services,
+        defer_exit_boot_services: config.defer_exit_boot_services,
+        pcid: config.pcid,
+        five_level_paging: config.five_level_paging,
+        kaslr_bits: config.kaslr_bits as u32,
+        cr0_set: config.cr0_set,
+        cr0_clear: config.cr0_clear,
+        cr4_set: config.cr4_set,
+        cr4_clear: config.cr4_clear,
+        efer_set: config.efer_set,
+        efer_clear: config.efer_clear,
+        boot_magic: config.boot_magic,
+    }
+}
+
+type GetMemoryMap = unsafe extern "efiapi" fn(
+    map_size: &mut usize,
+    map: *mut MemoryDescriptor,
+    map_key: &mut usize,
+    descriptor_size: &mut usize,
+    descriptor_version: &mut u32,
+) -> Status;
+
+/// The start of `EFI_BOOT_SERVICES`
+#[repr(C)]
+struct RawBootServices {
+    header: [u8; 24],
+    raise_tpl: usize,
+    restore_tpl: usize,
+    allocate_pages: usize,
+    free_pages: usize,
+    get_memory_map: GetMemoryMap,
+}
+
+/// The version of the firmware's memory descriptors, which
+/// `BootServices::memory_map_size` does not return
+fn memory_descriptor_version(bs: &BootServices) -> u32 {
+    let raw = unsafe { &*(bs as *const BootServices as *const RawBootServices) };
+    let (mut map_size, mut map_key, mut size, mut version) = (0, 0, 0, 0);
+    // without a buffer, only the sizes and the version are returned
+    let status = unsafe {
+        (raw.get_memory_map)(
+            &mut map_size,
+            core::ptr::null_mut(),
+            &mut map_key,
+            &mut size,
+            &mut version,
+        )
+    };
+    assert_eq!(
+        status,
+        Status::BUFFER_TOO_SMALL,
+        "failed to get memory map size"
+    );
+    version
+}
+
+/// Report `ranges` as conventional memory in the sorted `memory_map`: descriptors
+/// overlapping a range are cut to their parts outside of it, and the range is
+/// added as a descriptor of its own. Takes up to 3 descriptors per range from
+/// `spare`, and does not allocate, as it runs after exiting boot services.
+fn force_usable(
+    memory_map: &mut Vec<&'static MemoryDescriptor>,
+    ranges: &[MemoryRange],
+    spare: &'static mut [MemoryDescriptor],
+) {
+    let mut spare = spare.iter_mut();
+    for range in ranges {
+        // parts sticking out of the range, at most one on each side
+        let mut outside = [None, None];
+        memory_map.retain(|m| {
+            let end = m.phys_start + m.page_count * 0x1000;
+            if end <= range.start || range.end <= m.phys_start {
+                return true;
+            }
+            if m.phys_start < range.start {
+                outside[0] = Some((**m, m.phys_start, range.start));
+            }
+            if range.end < end {
+                outside[1] = Some((**m, range.end, end));
+            }
+            false
+        });
+        for (m, start, end) in outside.into_iter().flatten() {
+            let desc = spare.next().unwrap();
+            *desc = m;
+            desc.phys_start = start;
+            desc.virt_start = m.virt_start + (start - m.phys_start);
+            desc.page_count = (end - start) / 0x1000;
+            memory_map.push(desc);
+        }
+        let desc = spare.next().unwrap();
+        desc.ty = MemoryType::CONVENTIONAL;
+        desc.att = MemoryAttribute::WRITE_BACK;
+        desc.phys_start = range.start;
+        desc.virt_start = 0;
+        desc.page_count = (range.end - range.start) / 0x1000;
+        memory_map.push(desc);
+    }
+    memory_map.sort_unstable_by_key(|m| m.phys_start);
+}
+
+/// The physical memory the kernel needs at boot with `direct_map=minimal`:
+/// everything rboot loaded or allocated, the ACPI tables and the framebuffer
+fn essential_ranges(
+    mmap: &[&MemoryDescriptor],
+    graphic_info: Option<&GraphicInfo>,
+) -> Vec<MemoryRange> {
+    let types = [
+        MemoryType::LOADER_CODE,
+        MemoryType::LOADER_DATA,
+        MemoryType::ACPI_RECLAIM,
+        MemoryType::ACPI_NON_VOLATILE,
+    ];
+    let mut ranges: Vec<MemoryRange> = mmap
+        .iter()
+        .filter(|m| types.contains(&m.ty))
+        .map(|m| MemoryRange {
+            start: m.phys_start,
+            end: m.phys_start + m.page_count * 0x1000,
+        })
+        .collect();
+    if let Some(graphic_info) = graphic_info {
+        ranges.push(MemoryRange {
+            start: graphic_info.fb_addr,
+            end: graphic_info.fb_addr + graphic_info.fb_size,
+        });
+    }
+    ranges
+}
+
+/// The last component of `path`
+fn file_name(path: &str) -> &str {
+    path.rsplit(&['\\', '/'][..]).next().unwrap_or(path)
+}
+
+/// Stall for `ms` milliseconds before `phase`, for hardware which needs time to settle
+fn delay(bs: &BootServices, ms: u64, phase: &str) {
+    if ms != 0 {
+        info!("waiting {}ms before {}", ms, phase);
+        bs.stall(ms as usize * 1000);
+    }
+}
+
+/// Select the kernel from `kernel_path` followed by the `chain_next` kernels.
+///
+/// The n-th boot counted by `BOOT_COUNT_VAR` boots the n-th kernel,
+/// staying at the last one. Delete the variable to start over.
+/// If it can not be written, `kernel_path` is booted.
+/// Return the kernel, and the UEFI variable which selected it instead of `kernel_path`.
+fn select_kernel<'a>(rt: &RuntimeServices, config: &Config<'a>) -> (&'a str, Option<&'static str>) {
+    if config.ab_boot {
+        return select_ab_kernel(rt, config);
+    }
+    if config.chain_next.is_empty() {
+        return (config.kernel_path, None);
+    }
+    let count = var::read_u32(rt, BOOT_COUNT_VAR).unwrap_or(0);
+    if let Err(e) = var::write_u32(rt, BOOT_COUNT_VAR, count.saturating_add(1)) {
+        // the same kernel would be selected on every boot
+        warn!(
+            "failed to count the boot in {}: {:?}, booting kernel_path",
+            BOOT_COUNT_VAR,
+            e.status()
+        );
+        return (config.kernel_path, None);
+    }
+    let index = (count as usize).min(config.chain_next.len());
+    info!("boot count: {}, selecting kernel {}", count, index);
+    match index {
+        0 => (config.kernel_path, None),
+        _ => (config.chain_next[index - 1], Some(BOOT_COUNT_VAR)),
+    }
+}
+
+/// Get the options passed by the loader which started rboot, if any.
+///
+/// The UEFI shell passes the image path as the first option, which is dropped.
+fn load_options(bs: &BootServices, image: Handle) -> Option<String> {
+    let loaded_image = bs
+        .handle_protocol::<LoadedImage>(image)
+        .expect("failed to get LoadedImage");
+    let options = unsafe { &*loaded_image.get() }
+        .load_options_as_cstr16()
+        .ok()?;
+    let options = format!("{}", options);
+    let mut options = options.trim();
+    if let Some((first, rest)) = options.split_once(' ') {
+        if first.to_ascii_lowercase().ends_with(".efi") {
+            options = rest.trim_start();
+        }
+    } else if options.to_ascii_lowercase().ends_with(".efi") {
+        options = "";
+    }
+    if options.is_empty() {
+        None
+    } else {
+        Some(options.into())
+    }
+}
+
+/// The flags of the kernel mappings and the CR4 flags to set, with PCID
+/// `requested` by the config and `supported` by the CPU
+fn pcid_flags(requested: bool, supported: bool) -> (PageTableFlags, Cr4Flags) {
+    if requested && supported {
+        // kernel mappings are shared by all address spaces,
+        // so they can survive CR3 switches in the TLB
+        (
+            PageTableFlags::GLOBAL,
+            Cr4Flags::PCID | Cr4Flags::PAGE_GLOBAL,
+        )
+    } else {
+        (PageTableFlags::empty(), Cr4Flags::empty())
+    }
+}
+
+/// Check CPUID for process-context identifiers
+fn cpu_has_pcid() -> bool {
+    #[allow(unused_unsafe)]
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.ecx & (1 << 17) != 0
+}
+
+/// Check CPUID for the page attribute table
+fn cpu_has_pat() -> bool {
+    #[allow(unused_unsafe)]
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 16) != 0
+}
+
+/// Program PAT entry 1, which `PageTableFlags::WRITE_THROUGH` alone selects,
+/// as write-combining instead of write-through, like Linux does
+unsafe fn program_pat_write_combining() {
+    let mut pat = Msr::new(IA32_PAT);
+    let entries = pat.read();
+    // the cached lines of pages whose memory type changes must not be kept
+    asm!("wbinvd");
+    pat.write((entries & !(0xff << 8)) | (PAT_WRITE_COMBINING << 8));
+}
+
+/// Get current page table from CR3
+fn current_page_table() -> OffsetPageTable<'static> {
+    let p4_table_addr = Cr3::read().0.start_address().as_u64();
+    let p4_table = unsafe { &mut *(p4_table_addr as *mut PageTable) };
+    unsafe { OffsetPageTable::new(p4_table, VirtAddr::new(0)) }
+}
+
+/// Use `BootServices::allocate_pages()` as frame allocator
+struct UEFIFrameAllocator<'a>(&'a BootServices);
+
+impl UEFIFrameAllocator<'_> {
+    /// Allocate a frame below 1MiB, where application processors can start
+    fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let addr = self
+            .0
+            .allocate_pages(
+                AllocateType::MaxAddress(0xF_FFFF),
+                MemoryType::LOADER_DATA,
+                1,
+            )
+            .ok()?;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for UEFIFrameAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let addr = self
+            .0
+            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
+            .expect("failed to allocate frame");
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        Some(frame)
+    }
+}
+
+/// Flush all TLB entries, including global ones
+unsafe fn flush_tlb() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        // toggling global pages flushes everything
+        Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(cr4);
+    } else {
+        tlb::flush_all();
+    }
+}
+
+/// Set and clear the bits of control registers given by `config`
+unsafe fn override_control_registers(config: &Config) {
+    Cr0::write_raw((Cr0::read_raw() | config.cr0_set) & !config.cr0_clear);
+    Cr4::write_raw((Cr4::read_raw() | config.cr4_set) & !config.cr4_clear);
+    Efer::write_raw((Efer::read_raw() | config.efer_set) & !config.efer_clear);
+}
+
+/// Jump to ELF entry according to global variable `ENTRY`,
+/// with `rdi` and `rsi` as the first two arguments
+unsafe fn jump_to_entry(rdi: u64, rsi: u64, stacktop: u64) -> ! {
+    // Some firmware leaves the direction flag or alignment check (AC, bit 18)
+    // set, which early kernel code does not expect. Start the kernel with
+    // interrupts disabled and both flags clear, whatever the firmware did,
+    // also with `defer_exit_boot_services`, as the kernel sets up its own
+    // interrupt handling before enabling them.
+    asm!(
+        "cli",
+        "cld",
+        "pushfq",
+        "btr qword ptr [rsp], 18",
+        "popfq",
+        "mov rsp, {}",
+        "call {}",
+        in(reg) stacktop,
+        in(reg) ENTRY,
+        in("rdi") rdi,
+        in("rsi") rsi
+    );
+    loop {
+        asm!("nop");
+    }
+}
+
+/// The entry point of kernel, set by BSP.
+static mut ENTRY: usize = 0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcid_flags_need_request_and_support() {
+        let none = (PageTableFlags::empty(), Cr4Flags::empty());
+        assert_eq!(pcid_flags(false, false), none);
+        assert_eq!(pcid_flags(false, true), none);
+        assert_eq!(pcid_flags(true, false), none);
+        assert_eq!(
+            pcid_flags(true, true),
+            (
+                PageTableFlags::GLOBAL,
+                Cr4Flags::PCID | Cr4Flags::PAGE_GLOBAL
+            )
+        );
+    }
+}
@@ -1,74 +1,1294 @@
 // TODO: use no_std serde crate to parse
 
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 use core::str::FromStr;
 
+use crate::page_table::BssInit;
+use rboot::MemoryRange;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+
 /// Config for the bootloader
 #[derive(Debug)]
 pub struct Config<'a> {
     /// The address at which the kernel stack is placed
     pub kernel_stack_address: u64,
+    /// Place the kernel stack above the kernel image instead, set by `kernel_stack_address=auto`
+    pub kernel_stack_auto: bool,
+    /// The number of unmapped pages between the kernel image and an automatically placed stack
+    pub kernel_stack_gap: u64,
     /// The size of the kernel stack, given in number of 4KiB pages
     pub kernel_stack_size: u64,
+    /// Map only the top `lazy_stack_pages` of the stack, if the kernel allows it
+    pub lazy_stack: bool,
+    /// The number of pages at the top of the stack mapped with `lazy_stack`
+    pub lazy_stack_pages: u64,
+    /// Refuse to boot if the page below the kernel stack is mapped, so that
+    /// overflowing the stack faults
+    pub stack_guard: bool,
     /// The offset into the virtual address space where the physical memory is mapped
     pub physical_memory_offset: u64,
+    /// Map physical memory at `physical_memory_offset` as executable
+    pub direct_map_exec: bool,
+    /// Which physical memory is mapped at `physical_memory_offset`
+    pub direct_map: DirectMap,
+    /// With `DirectMap::Minimal`, also map physical memory below this address. 0 for none
+    pub direct_map_window: u64,
+    /// Leave physical memory which does not fit at `physical_memory_offset` unmapped
+    pub direct_map_clamp: bool,
+    /// Check `physical_memory_offset` against the `__phys_offset` symbol of the kernel
+    pub check_phys_offset: bool,
     /// The path of kernel ELF
     pub kernel_path: &'a str,
+    /// The path of a file with the kernel's symbols or debug info, passed as it is
+    pub kernel_symbols_path: Option<&'a str>,
+    /// Check that the kernel's first LOAD segment starts with this `u64`. 0 to disable
+    pub kernel_magic: u64,
+    /// How the kernel file is compressed
+    pub kernel_compression: KernelCompression,
+    /// Whether the kernel is an ELF or a PE/COFF EFI application
+    pub kernel_format: KernelFormat,
+    /// Kernels booted after `kernel_path` on subsequent boots
+    pub chain_next: Vec<&'a str>,
+    /// Boot `fallback_kernel_path` once `kernel_path` failed to boot successfully
+    pub ab_boot: bool,
+    /// The kernel booted with `ab_boot` once `kernel_path` failed
+    pub fallback_kernel_path: Option<&'a str>,
+    /// The number of boots `kernel_path` may fail with `ab_boot`
+    pub ab_max_attempts: u32,
+    /// The UEFI variable the kernel sets to nonzero once it booted successfully
+    pub ab_success_var: &'a str,
     /// The resolution of graphic output
     pub resolution: Option<(usize, usize)>,
+    /// The most graphic modes passed as `available_graphic_modes`
+    pub max_graphic_modes: usize,
+    /// The path of a tar archive, from which the kernel and initramfs are loaded
+    pub bundle_path: Option<&'a str>,
+    /// The path of a FAT image, from which the config, kernel and initramfs are loaded
+    pub boot_image: Option<&'a str>,
     /// The path of initramfs
     pub initramfs: Option<&'a str>,
+    /// Boot without initramfs if it can not be loaded
+    pub initramfs_optional: bool,
+    /// Load initramfs to this physical address. 0 for anywhere
+    pub initramfs_addr: u64,
+    /// Load initramfs below this physical address. 0 for anywhere
+    pub initramfs_max_addr: u64,
+    /// The directory whose files are all loaded as modules
+    pub modules_dir: Option<&'a str>,
+    /// Modules given by `module.<index>.path` and `module.<index>.cmdline`, by index,
+    /// or by `module` lines, which take the next index
+    pub modules: Vec<ModuleConfig<'a>>,
+    /// Kernels for machines given by `machine.<index>.*` keys, by index
+    pub machines: Vec<MachineConfig<'a>>,
     /// Kernel command line
     pub cmdline: &'a str,
+    /// Kernel command line arguments, joined before `cmdline`
+    pub args: Vec<&'a str>,
+    /// Wait this many milliseconds before loading the kernel
+    pub delay_before_load: u64,
+    /// Wait this many milliseconds before exiting boot services
+    pub delay_before_exit: u64,
+    /// Retry opening a file this many times, for removable media which is not ready
+    pub file_open_retries: u32,
+    /// Abort loading a file if a read makes no progress within this many seconds,
+    /// reset the machine by the watchdog after twice as many. 0 to disable
+    pub io_timeout: u64,
+    /// Fill bss with this repeating pattern instead of zeros, for debugging. 0 to disable
+    pub bss_fill: u64,
+    /// The width of `bss_fill` in bytes, from the number of its hex digits
+    pub bss_fill_width: u64,
+    /// Zero bss in the loader. If off, the kernel must zero its bss itself
+    pub zero_bss: bool,
+    /// Align the kernel in physical memory like its segments are aligned virtually
+    pub preserve_phys_alignment: bool,
+    /// Build the kernel page table in a copy of the firmware one, instead of in place
+    pub fresh_page_table: bool,
+    /// Identity map the kernel file as executable, besides its ELF mapping
+    pub identity_map_kernel: bool,
+    /// Allocate and identity map a page below 1MiB, passed as `ap_trampoline_addr`
+    pub ap_trampoline: bool,
+    /// Clear the accessed and dirty bits of the kernel and stack pages
+    pub clear_access_bits: bool,
+    /// Refuse to map the page at virtual address 0, so that null pointers fault
+    pub protect_null: bool,
+    /// Keep boot services code/data mapped and report them to the kernel
+    pub keep_boot_services: bool,
+    /// Physical ranges reported to the kernel as conventional memory,
+    /// whatever the firmware says about them
+    pub force_usable: Vec<MemoryRange>,
+    /// Jump to the kernel without exiting boot services, for it to exit them
+    pub defer_exit_boot_services: bool,
+    /// Only log errors, and clear the screen before booting
+    pub quiet: bool,
+    /// Log the memory map right before exiting boot services
+    pub dump_memory_map: bool,
+    /// Draw a test pattern to the framebuffer, to check the graphic info
+    pub fb_test: bool,
+    /// Allocate a buffer of the framebuffer size, passed as `fb_backbuffer_addr`
+    pub fb_backbuffer: bool,
+    /// Pass the text console size and cursor position as `early_console`
+    pub early_console: bool,
+    /// Enable PCID and global pages, and map the kernel and stack as global
+    pub pcid: bool,
+    /// Bits set in CR0 right before jumping to the kernel
+    pub cr0_set: u64,
+    /// Bits cleared in CR0 right before jumping to the kernel
+    pub cr0_clear: u64,
+    /// Bits set in CR4 right before jumping to the kernel
+    pub cr4_set: u64,
+    /// Bits cleared in CR4 right before jumping to the kernel
+    pub cr4_clear: u64,
+    /// Bits set in EFER right before jumping to the kernel
+    pub efer_set: u64,
+    /// Bits cleared in EFER right before jumping to the kernel
+    pub efer_clear: u64,
+    /// Switch to 5-level paging before jumping to the kernel, if supported
+    pub five_level_paging: bool,
+    /// Slide the kernel by a random multiple of 2MiB of up to this many bits. 0 to disable
+    pub kaslr_bits: u8,
+    /// Refuse to boot if `BootInfo` points to memory the kernel page table does not map,
+    /// or if the kernel entry is not in the higher half
+    pub strict: bool,
+    /// Log the CRC-32 of the config, kernel and initramfs after loading them
+    pub log_checksums: bool,
+    /// The path of a file on the boot volume to write the log to before exiting boot services
+    pub log_file: Option<&'a str>,
+    /// Pass the config to the kernel as `config`
+    pub pass_config: bool,
+    /// The register holding the `BootInfo` pointer at the kernel entry
+    pub bootinfo_register: BootInfoRegister,
+    /// The value passed in the other one of `rdi` and `rsi`
+    pub boot_magic: u64,
+    /// The keys set by the config file with their line numbers, in order
+    pub lines: Vec<(&'a str, usize)>,
+    /// The keys set while booting, after parsing, with their origins, in order
+    pub overrides: Vec<(&'a str, Origin)>,
+}
+
+/// Where the value of a config key came from, other than the built-in defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// A `RBOOT_DEFAULT_*` environment variable at compile time
+    Build,
+    /// The line of the config file with this number
+    Line(usize),
+    /// The load options passed by the loader which started rboot
+    LoadOptions,
+    /// The UEFI variable with this name selected the value
+    Variable(&'static str),
+    /// The `machine.<index>` entry with this index matched the SMBIOS system information
+    Machine(usize),
+}
+
+/// How the kernel file is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelCompression {
+    /// Detected by the magic number of the file
+    Auto,
+    /// Not compressed
+    None,
+    /// LZ4 frames
+    Lz4,
+}
+
+/// The executable format of the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// PE/COFF if the file has a PE signature, ELF otherwise
+    Auto,
+    Elf,
+    /// A PE/COFF EFI application, started by the firmware
+    Pe,
+}
+
+/// Which physical memory is mapped at `physical_memory_offset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectMap {
+    /// All of it
+    Full,
+    /// Only what the kernel needs at boot, and `direct_map_window`
+    Minimal,
 }
 
-const DEFAULT_CONFIG: Config = Config {
-    kernel_stack_address: 0xFFFF_FF01_0000_0000,
-    kernel_stack_size: 512,
-    physical_memory_offset: 0xFFFF_8000_0000_0000,
-    kernel_path: "\\EFI\\rCore\\kernel.elf",
+/// The register holding the `BootInfo` pointer at the kernel entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoRegister {
+    /// The first argument of the System V calling convention
+    Rdi,
+    /// The second one, with `boot_magic` as the first
+    Rsi,
+}
+
+/// A kernel for machines whose SMBIOS system information matches,
+/// given by `machine.<index>.*` keys
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MachineConfig<'a> {
+    /// The product name to match, if not empty
+    pub product: &'a str,
+    /// The serial number to match, if not empty
+    pub serial: &'a str,
+    /// The kernel to boot instead of `kernel_path`
+    pub kernel_path: &'a str,
+}
+
+/// A module given by `module.<index>.*` keys
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModuleConfig<'a> {
+    /// The path of the file
+    pub path: &'a str,
+    /// The command line passed to the kernel with the module
+    pub cmdline: &'a str,
+}
+
+/// Error of an invalid config
+#[derive(Debug)]
+pub enum ConfigError<'a> {
+    /// The file is neither valid UTF-8 nor UTF-16 with a BOM
+    InvalidUtf8,
+    /// A line has no `=`
+    MissingValue { line: usize },
+    /// A value is not a number
+    BadInteger {
+        line: usize,
+        key: &'a str,
+        value: &'a str,
+    },
+    /// A value is not `on`, `off`, `true`, `false`, `1` or `0`
+    BadBoolean {
+        line: usize,
+        key: &'a str,
+        value: &'a str,
+    },
+    /// A value is not one of those the key accepts
+    BadValue {
+        line: usize,
+        key: &'a str,
+        value: &'a str,
+    },
+    /// A `resolution` is not `<width>x<height>`
+    BadResolution { line: usize, value: &'a str },
+    /// `kernel_stack_size` is zero
+    BadStackSize,
+    /// `kernel_stack_gap` is zero
+    BadStackGap,
+    /// `lazy_stack_pages` is zero
+    BadLazyStackPages,
+    /// A control register mask has bits which are not defined for the register
+    BadRegisterMask(&'static str),
+    /// `kaslr_bits` is larger than `MAX_KASLR_BITS`
+    KaslrBitsTooLarge,
+    /// `physical_memory_offset` is not aligned for 2MiB pages
+    MisalignedPhysOffset,
+    /// A `force_usable` range is empty or not page aligned
+    BadForceUsable(MemoryRange),
+    /// `ab_boot` is set without `fallback_kernel_path`
+    MissingFallbackKernel,
+    /// `module.<index>.path` is missing for a module index below the largest one
+    MissingModulePath(usize),
+    /// `module` lines and `module.<index>.*` keys are both given
+    MixedModuleForms { line: usize },
+    /// More than `MAX_MODULES` modules are given
+    TooManyModules { line: usize },
+    /// `machine.<index>` has no `kernel_path`, or neither `product` nor `serial`
+    IncompleteMachine(usize),
+}
+
+impl fmt::Display for ConfigError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidUtf8 => write!(f, "not valid UTF-8 or UTF-16"),
+            ConfigError::MissingValue { line } => write!(f, "line {}: missing '='", line),
+            ConfigError::BadInteger { line, key, value } => {
+                write!(f, "line {}: invalid number for {}: {}", line, key, value)
+            }
+            ConfigError::BadBoolean { line, key, value } => {
+                write!(f, "line {}: invalid boolean for {}: {}", line, key, value)
+            }
+            ConfigError::BadValue { line, key, value } => {
+                write!(f, "line {}: invalid value for {}: {}", line, key, value)
+            }
+            ConfigError::BadResolution { line, value } => write!(
+                f,
+                "line {}: invalid resolution {}, expected <width>x<height>",
+                line, value
+            ),
+            ConfigError::BadStackSize => write!(f, "kernel_stack_size must not be zero"),
+            ConfigError::BadStackGap => write!(f, "kernel_stack_gap must not be zero"),
+            ConfigError::BadLazyStackPages => write!(f, "lazy_stack_pages must not be zero"),
+            ConfigError::BadRegisterMask(key) => {
+                write!(f, "{} has bits not defined for the register", key)
+            }
+            ConfigError::KaslrBitsTooLarge => {
+                write!(f, "kaslr_bits must be at most {}", MAX_KASLR_BITS)
+            }
+            ConfigError::MisalignedPhysOffset => write!(
+                f,
+                "physical_memory_offset must be aligned to {:#x}, as it is mapped with 2MiB pages",
+                PHYS_OFFSET_ALIGN
+            ),
+            ConfigError::BadForceUsable(range) => write!(
+                f,
+                "force_usable range {:#x}..{:#x} must be page aligned and not empty",
+                range.start, range.end
+            ),
+            ConfigError::MissingFallbackKernel => {
+                write!(f, "ab_boot requires fallback_kernel_path")
+            }
+            ConfigError::MissingModulePath(index) => {
+                write!(f, "module.{}.path is missing", index)
+            }
+            ConfigError::MixedModuleForms { line } => write!(
+                f,
+                "line {}: module lines can not be mixed with module.<index>.* keys",
+                line
+            ),
+            ConfigError::TooManyModules { line } => {
+                write!(f, "line {}: more than {} modules", line, MAX_MODULES)
+            }
+            ConfigError::IncompleteMachine(index) => write!(
+                f,
+                "machine.{} needs kernel_path, and product or serial",
+                index
+            ),
+        }
+    }
+}
+
+/// Stacks larger than this many pages (16MiB) are likely a mistake
+const MAX_STACK_PAGES: u64 = 0x1000;
+
+/// Physical memory is mapped with 2MiB pages
+const PHYS_OFFSET_ALIGN: u64 = 0x20_0000;
+
+/// Module indices must be below this
+const MAX_MODULES: usize = 64;
+/// Machine indices must be below this
+const MAX_MACHINES: usize = 64;
+
+/// 2MiB slides of more bits do not fit in a canonical half
+const MAX_KASLR_BITS: u8 = 26;
+
+/// Built-in defaults, which can be overridden by `RBOOT_DEFAULT_*` environment
+/// variables at compile time
+pub const DEFAULT_CONFIG: Config = Config {
+    kernel_stack_address: default_u64(
+        option_env!("RBOOT_DEFAULT_KERNEL_STACK_ADDRESS"),
+        0xFFFF_FF01_0000_0000,
+    ),
+    kernel_stack_auto: false,
+    kernel_stack_gap: 1,
+    kernel_stack_size: default_u64(option_env!("RBOOT_DEFAULT_KERNEL_STACK_SIZE"), 512),
+    lazy_stack: false,
+    lazy_stack_pages: 1,
+    stack_guard: true,
+    physical_memory_offset: default_u64(
+        option_env!("RBOOT_DEFAULT_PHYSICAL_MEMORY_OFFSET"),
+        0xFFFF_8000_0000_0000,
+    ),
+    direct_map_exec: false,
+    direct_map: DirectMap::Full,
+    direct_map_window: 0x10_0000,
+    direct_map_clamp: false,
+    check_phys_offset: false,
+    kernel_path: default_str(
+        option_env!("RBOOT_DEFAULT_KERNEL_PATH"),
+        "\\EFI\\rCore\\kernel.elf",
+    ),
+    kernel_symbols_path: None,
+    kernel_magic: 0,
+    kernel_compression: KernelCompression::Auto,
+    kernel_format: KernelFormat::Auto,
+    chain_next: Vec::new(),
+    ab_boot: false,
+    fallback_kernel_path: None,
+    ab_max_attempts: 3,
+    ab_success_var: "RbootBootSuccess",
     resolution: None,
-    initramfs: None,
-    cmdline: "",
+    max_graphic_modes: 64,
+    bundle_path: None,
+    boot_image: None,
+    initramfs: match option_env!("RBOOT_DEFAULT_INITRAMFS") {
+        Some(path) => Some(path),
+        None => None,
+    },
+    initramfs_optional: false,
+    initramfs_addr: 0,
+    initramfs_max_addr: 0,
+    modules_dir: None,
+    modules: Vec::new(),
+    machines: Vec::new(),
+    cmdline: default_str(option_env!("RBOOT_DEFAULT_CMDLINE"), ""),
+    args: Vec::new(),
+    delay_before_load: 0,
+    delay_before_exit: 0,
+    file_open_retries: 3,
+    io_timeout: 0,
+    bss_fill: 0,
+    bss_fill_width: 8,
+    zero_bss: true,
+    preserve_phys_alignment: false,
+    fresh_page_table: false,
+    identity_map_kernel: false,
+    ap_trampoline: false,
+    clear_access_bits: false,
+    protect_null: true,
+    keep_boot_services: false,
+    force_usable: Vec::new(),
+    defer_exit_boot_services: false,
+    quiet: false,
+    dump_memory_map: false,
+    fb_test: false,
+    fb_backbuffer: false,
+    early_console: false,
+    pcid: false,
+    cr0_set: 0,
+    cr0_clear: 0,
+    cr4_set: 0,
+    cr4_clear: 0,
+    efer_set: 0,
+    efer_clear: 0,
+    five_level_paging: false,
+    kaslr_bits: 0,
+    strict: false,
+    log_checksums: false,
+    log_file: None,
+    pass_config: false,
+    bootinfo_register: BootInfoRegister::Rdi,
+    boot_magic: 0,
+    lines: Vec::new(),
+    overrides: Vec::new(),
 };
 
+/// The keys whose defaults can be overridden at compile time, if they are
+const BUILD_DEFAULTS: [(&str, Option<&str>); 6] = [
+    (
+        "kernel_stack_address",
+        option_env!("RBOOT_DEFAULT_KERNEL_STACK_ADDRESS"),
+    ),
+    (
+        "kernel_stack_size",
+        option_env!("RBOOT_DEFAULT_KERNEL_STACK_SIZE"),
+    ),
+    (
+        "physical_memory_offset",
+        option_env!("RBOOT_DEFAULT_PHYSICAL_MEMORY_OFFSET"),
+    ),
+    ("kernel_path", option_env!("RBOOT_DEFAULT_KERNEL_PATH")),
+    ("initramfs", option_env!("RBOOT_DEFAULT_INITRAMFS")),
+    ("cmdline", option_env!("RBOOT_DEFAULT_CMDLINE")),
+];
+
+/// Decode the config file as UTF-8, or as UTF-16 if it starts with a UTF-16 BOM,
+/// as saved by some Windows editors. A UTF-8 BOM is skipped. UTF-16 with an odd
+/// number of bytes is invalid.
+fn decode(content: &[u8]) -> Result<&str, ConfigError<'static>> {
+    let from_bytes: fn([u8; 2]) -> u16 = match content {
+        [0xFF, 0xFE, ..] => u16::from_le_bytes,
+        [0xFE, 0xFF, ..] => u16::from_be_bytes,
+        [0xEF, 0xBB, 0xBF, rest @ ..] => {
+            return core::str::from_utf8(rest).map_err(|_| ConfigError::InvalidUtf8)
+        }
+        _ => return core::str::from_utf8(content).map_err(|_| ConfigError::InvalidUtf8),
+    };
+    if content.len() % 2 != 0 {
+        return Err(ConfigError::InvalidUtf8);
+    }
+    let units = content[2..]
+        .chunks_exact(2)
+        .map(|unit| from_bytes([unit[0], unit[1]]));
+    let content = char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map_err(|_| ConfigError::InvalidUtf8)?;
+    // the config borrows from the file contents, which live forever as well
+    Ok(Box::leak(content.into_boxed_str()))
+}
+
+/// Use the compile time `env` value if it is set
+const fn default_str(env: Option<&'static str>, fallback: &'static str) -> &'static str {
+    match env {
+        Some(value) => value,
+        None => fallback,
+    }
+}
+
+/// Parse the compile time `env` value if it is set, like `parse_int`
+const fn default_u64(env: Option<&str>, fallback: u64) -> u64 {
+    match env {
+        Some(value) => match parse_int(value) {
+            Some(value) => value,
+            None => panic!("invalid number in RBOOT_DEFAULT_* value"),
+        },
+        None => fallback,
+    }
+}
+
+/// Parse a number in decimal, or in hex with a `0x` prefix, with optional `_`
+/// separators, or none if it has no digits, other characters or overflows
+pub const fn parse_int(value: &str) -> Option<u64> {
+    let bytes = value.as_bytes();
+    let (radix, mut i) = if bytes.len() > 2 && bytes[0] == b'0' && bytes[1] == b'x' {
+        (16, 2)
+    } else {
+        (10, 0)
+    };
+    let mut value = 0u64;
+    let mut digits = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'_' => {
+                i += 1;
+                continue;
+            }
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => return None,
+        };
+        if digit as u64 >= radix {
+            return None;
+        }
+        value = match value.checked_mul(radix) {
+            Some(value) => match value.checked_add(digit as u64) {
+                Some(value) => value,
+                None => return None,
+            },
+            None => return None,
+        };
+        digits += 1;
+        i += 1;
+    }
+    if digits == 0 {
+        return None;
+    }
+    Some(value)
+}
+
+/// The width in bytes of the valid number `value` as a repeating pattern:
+/// half its hex digits including leading zeros, rounded up to a power of two,
+/// or 8 if it is decimal
+fn pattern_width(value: &str) -> u64 {
+    match value.strip_prefix("0x") {
+        Some(hex) => {
+            let digits = hex.bytes().filter(|&b| b != b'_').count() as u64;
+            ((digits + 1) / 2).next_power_of_two().min(8)
+        }
+        None => 8,
+    }
+}
+
 impl<'a> Config<'a> {
-    pub fn parse(content: &'a [u8]) -> Self {
-        let content = core::str::from_utf8(content).expect("failed to parse config as utf8");
+    /// Parse the `key=value` lines of a config file, which may end with
+    /// `\n` or `\r\n`, with or without one after the last line.
+    /// Fails at the first malformed line.
+    pub fn parse(content: &'a [u8]) -> Result<Self, ConfigError<'a>> {
+        let content = decode(content)?;
         let mut config = DEFAULT_CONFIG;
-        for line in content.split('\n') {
+        for (number, line) in content.lines().enumerate() {
             let line = line.trim();
             // skip empty and comment
             if line.is_empty() || line.starts_with('#') {
                 continue;
             }
             // parse 'key=value'
-            let mut iter = line.splitn(2, '=');
-            let key = iter.next().expect("failed to parse key");
-            let value = iter.next().expect("failed to parse value");
-            config.process(key, value);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(ConfigError::MissingValue { line: number + 1 })?;
+            config.process(number + 1, key, value)?;
+            config.lines.push((key, number + 1));
+        }
+        Ok(config)
+    }
+
+    /// Record that `key` was set from `origin` while booting
+    pub fn set_origin(&mut self, key: &'a str, origin: Origin) {
+        self.overrides.push((key, origin));
+    }
+
+    /// Where each key which is not at its built-in default came from: those
+    /// overridden at compile time and not set by the config file, then the
+    /// lines of the config file, then the keys set while booting.
+    /// A key may be listed more than once, the last one is in effect.
+    pub fn origins(&self) -> impl Iterator<Item = (&'a str, Origin)> + '_ {
+        let build = BUILD_DEFAULTS
+            .iter()
+            .filter(|(key, value)| {
+                value.is_some() && !self.lines.iter().any(|(line_key, _)| line_key == key)
+            })
+            .map(|&(key, _)| (key, Origin::Build));
+        let lines = self
+            .lines
+            .iter()
+            .map(|&(key, number)| (key, Origin::Line(number)));
+        build.chain(lines).chain(self.overrides.iter().copied())
+    }
+
+    /// Check for values which can not boot
+    pub fn validate(&self) -> Result<(), ConfigError<'a>> {
+        if self.kernel_stack_size == 0 {
+            return Err(ConfigError::BadStackSize);
+        }
+        if self.kernel_stack_gap == 0 {
+            return Err(ConfigError::BadStackGap);
+        }
+        if self.lazy_stack_pages == 0 {
+            return Err(ConfigError::BadLazyStackPages);
+        }
+        if self.kernel_stack_size > MAX_STACK_PAGES {
+            warn!(
+                "kernel_stack_size of {} pages is unusually large",
+                self.kernel_stack_size
+            );
+        }
+        if self.physical_memory_offset % PHYS_OFFSET_ALIGN != 0 {
+            return Err(ConfigError::MisalignedPhysOffset);
+        }
+        if self.ab_boot && self.fallback_kernel_path.is_none() {
+            return Err(ConfigError::MissingFallbackKernel);
+        }
+        if let Some(index) = self.modules.iter().position(|m| m.path.is_empty()) {
+            return Err(ConfigError::MissingModulePath(index));
+        }
+        let incomplete = self
+            .machines
+            .iter()
+            .position(|m| m.kernel_path.is_empty() || m.product.is_empty() && m.serial.is_empty());
+        if let Some(index) = incomplete {
+            return Err(ConfigError::IncompleteMachine(index));
+        }
+        if self.kaslr_bits > MAX_KASLR_BITS {
+            return Err(ConfigError::KaslrBitsTooLarge);
+        }
+        let masks = [
+            ("cr0_set", self.cr0_set, Cr0Flags::all().bits()),
+            ("cr0_clear", self.cr0_clear, Cr0Flags::all().bits()),
+            ("cr4_set", self.cr4_set, Cr4Flags::all().bits()),
+            ("cr4_clear", self.cr4_clear, Cr4Flags::all().bits()),
+            ("efer_set", self.efer_set, EferFlags::all().bits()),
+            ("efer_clear", self.efer_clear, EferFlags::all().bits()),
+        ];
+        for (name, mask, defined) in masks {
+            if mask & !defined != 0 {
+                return Err(ConfigError::BadRegisterMask(name));
+            }
+        }
+        for &range in &self.force_usable {
+            if range.start % 0x1000 != 0 || range.end % 0x1000 != 0 || range.start >= range.end {
+                return Err(ConfigError::BadForceUsable(range));
+            }
+        }
+        Ok(())
+    }
+
+    /// Join all `arg` entries and `cmdline` with spaces, in order
+    pub fn kernel_cmdline(&self) -> String {
+        let mut parts = self.args.clone();
+        if !self.cmdline.is_empty() {
+            parts.push(self.cmdline);
+        }
+        parts.join(" ")
+    }
+
+    /// How the kernel's bss is initialized, from `zero_bss` and `bss_fill`
+    pub fn bss_init(&self) -> BssInit {
+        match (self.zero_bss, self.bss_fill) {
+            (false, _) => BssInit::Skip,
+            (true, 0) => BssInit::Zero,
+            (true, pattern) => BssInit::Fill {
+                pattern,
+                width: self.bss_fill_width,
+            },
         }
-        config
     }
 
-    fn process(&mut self, key: &str, value: &'a str) {
-        let r10 = || u64::from_str(value).unwrap();
-        let r16 = || u64::from_str_radix(&value[2..], 16).unwrap();
+    /// Set `key` to `value`, given on `line`
+    fn process(
+        &mut self,
+        line: usize,
+        key: &'a str,
+        value: &'a str,
+    ) -> Result<(), ConfigError<'a>> {
+        let bad_integer = || ConfigError::BadInteger { line, key, value };
+        let bad_value = || ConfigError::BadValue { line, key, value };
+        let int = || parse_int(value).ok_or_else(bad_integer);
+        let rb = || match value {
+            "on" | "true" | "1" => Ok(true),
+            "off" | "false" | "0" => Ok(false),
+            _ => Err(ConfigError::BadBoolean { line, key, value }),
+        };
         match key {
-            "kernel_stack_address" => self.kernel_stack_address = r16(),
-            "kernel_stack_size" => self.kernel_stack_size = r10(),
+            "kernel_stack_address" if value == "auto" => self.kernel_stack_auto = true,
+            "kernel_stack_address" => {
+                self.kernel_stack_address = int()?;
+                self.kernel_stack_auto = false;
+            }
+            "kernel_stack_gap" => self.kernel_stack_gap = int()?,
+            "kernel_stack_size" => self.kernel_stack_size = int()?,
+            "lazy_stack" => self.lazy_stack = rb()?,
+            "lazy_stack_pages" => self.lazy_stack_pages = int()?,
+            "stack_guard" => self.stack_guard = rb()?,
             "physical_memory_offset" => {
-                self.physical_memory_offset = r16();
+                self.physical_memory_offset = int()?;
             }
+            "direct_map_exec" => self.direct_map_exec = rb()?,
+            "direct_map" => {
+                self.direct_map = match value {
+                    "full" => DirectMap::Full,
+                    "minimal" => DirectMap::Minimal,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "direct_map_window" => self.direct_map_window = int()?,
+            "direct_map_clamp" => self.direct_map_clamp = rb()?,
+            "kernel_compression" => {
+                self.kernel_compression = match value {
+                    "auto" => KernelCompression::Auto,
+                    "none" => KernelCompression::None,
+                    "lz4" => KernelCompression::Lz4,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "kernel_format" => {
+                self.kernel_format = match value {
+                    "auto" => KernelFormat::Auto,
+                    "elf" => KernelFormat::Elf,
+                    "pe" => KernelFormat::Pe,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "check_phys_offset" => self.check_phys_offset = rb()?,
             "kernel_path" => self.kernel_path = value,
+            "kernel_symbols_path" => self.kernel_symbols_path = Some(value),
+            "kernel_magic" => self.kernel_magic = int()?,
+            "chain_next" => self.chain_next.push(value),
+            "ab_boot" => self.ab_boot = rb()?,
+            "fallback_kernel_path" => self.fallback_kernel_path = Some(value),
+            "ab_max_attempts" => {
+                self.ab_max_attempts = u32::try_from(int()?).map_err(|_| bad_integer())?
+            }
+            "ab_success_var" => self.ab_success_var = value,
             "resolution" => {
-                let mut iter = value.split('x');
-                let x = iter.next().unwrap().parse::<usize>().unwrap();
-                let y = iter.next().unwrap().parse::<usize>().unwrap();
-                self.resolution = Some((x, y));
+                let resolution = value
+                    .split_once('x')
+                    .and_then(|(x, y)| {
+                        // within u32, as passed in `BootConfig`
+                        let size = |s| Some(u32::try_from(parse_int(s)?).ok()? as usize);
+                        Some((size(x)?, size(y)?))
+                    })
+                    .ok_or(ConfigError::BadResolution { line, value })?;
+                self.resolution = Some(resolution);
+            }
+            "max_graphic_modes" => {
+                self.max_graphic_modes = usize::try_from(int()?).map_err(|_| bad_integer())?
             }
+            "bundle_path" => self.bundle_path = Some(value),
+            "boot_image" => self.boot_image = Some(value),
             "initramfs" => self.initramfs = Some(value),
+            "initramfs_optional" => self.initramfs_optional = rb()?,
+            "initramfs_addr" => self.initramfs_addr = int()?,
+            "initramfs_max_addr" => self.initramfs_max_addr = int()?,
+            "modules_dir" => self.modules_dir = Some(value),
             "cmdline" => self.cmdline = value,
+            "arg" => self.args.push(value),
+            "delay_before_load" => self.delay_before_load = int()?,
+            "delay_before_exit" => self.delay_before_exit = int()?,
+            "file_open_retries" => {
+                self.file_open_retries = u32::try_from(int()?).map_err(|_| bad_integer())?
+            }
+            "io_timeout" => self.io_timeout = int()?,
+            "bss_fill" => {
+                self.bss_fill = int()?;
+                self.bss_fill_width = pattern_width(value);
+            }
+            "zero_bss" => self.zero_bss = rb()?,
+            "preserve_phys_alignment" => self.preserve_phys_alignment = rb()?,
+            "fresh_page_table" => self.fresh_page_table = rb()?,
+            "identity_map_kernel" => self.identity_map_kernel = rb()?,
+            "ap_trampoline" => self.ap_trampoline = rb()?,
+            "clear_access_bits" => self.clear_access_bits = rb()?,
+            "protect_null" => self.protect_null = rb()?,
+            "keep_boot_services" => self.keep_boot_services = rb()?,
+            "force_usable" => {
+                let (base, size) = value.split_once(':').ok_or_else(bad_value)?;
+                let (start, size) = parse_int(base).zip(parse_int(size)).ok_or_else(bad_value)?;
+                self.force_usable.push(MemoryRange {
+                    start,
+                    end: start.checked_add(size).ok_or_else(bad_value)?,
+                });
+            }
+            "defer_exit_boot_services" => self.defer_exit_boot_services = rb()?,
+            "quiet" => self.quiet = rb()?,
+            "dump_memory_map" => self.dump_memory_map = rb()?,
+            "fb_test" => self.fb_test = rb()?,
+            "fb_backbuffer" => self.fb_backbuffer = rb()?,
+            "early_console" => self.early_console = rb()?,
+            "pcid" => self.pcid = rb()?,
+            "cr0_set" => self.cr0_set = int()?,
+            "cr0_clear" => self.cr0_clear = int()?,
+            "cr4_set" => self.cr4_set = int()?,
+            "cr4_clear" => self.cr4_clear = int()?,
+            "efer_set" => self.efer_set = int()?,
+            "efer_clear" => self.efer_clear = int()?,
+            "five_level_paging" => self.five_level_paging = rb()?,
+            "kaslr_bits" => self.kaslr_bits = u8::try_from(int()?).map_err(|_| bad_integer())?,
+            "strict" => self.strict = rb()?,
+            "log_checksums" => self.log_checksums = rb()?,
+            "log_file" => self.log_file = Some(value),
+            "pass_config" => self.pass_config = rb()?,
+            "bootinfo_register" => {
+                self.bootinfo_register = match value {
+                    "rdi" => BootInfoRegister::Rdi,
+                    "rsi" => BootInfoRegister::Rsi,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "boot_magic" => self.boot_magic = int()?,
+            "module" => {
+                // a later `module.<index>.*` key would overwrite it by its index
+                if self.lines.iter().any(|(key, _)| key.starts_with("module.")) {
+                    return Err(ConfigError::MixedModuleForms { line });
+                }
+                if self.modules.len() == MAX_MODULES {
+                    return Err(ConfigError::TooManyModules { line });
+                }
+                self.modules.push(ModuleConfig {
+                    path: value,
+                    cmdline: "",
+                });
+            }
+            _ if key.starts_with("module.") => self.process_module(line, key, value)?,
+            _ if key.starts_with("machine.") => self.process_machine(key, value),
             _ => warn!("undefined config key: {}", key),
         }
+        Ok(())
+    }
+
+    /// Process a `module.<index>.path` or `module.<index>.cmdline` key, given on `line`
+    fn process_module(
+        &mut self,
+        line: usize,
+        key: &str,
+        value: &'a str,
+    ) -> Result<(), ConfigError<'a>> {
+        if self.lines.iter().any(|&(key, _)| key == "module") {
+            return Err(ConfigError::MixedModuleForms { line });
+        }
+        let (index, field) = match indexed_key(key, usize::MAX) {
+            Some((index, _)) if index >= MAX_MODULES => {
+                return Err(ConfigError::TooManyModules { line })
+            }
+            Some(key) => key,
+            None => {
+                warn!("undefined config key: {}", key);
+                return Ok(());
+            }
+        };
+        if self.modules.len() <= index {
+            self.modules.resize(index + 1, ModuleConfig::default());
+        }
+        match field {
+            "path" => self.modules[index].path = value,
+            "cmdline" => self.modules[index].cmdline = value,
+            _ => warn!("undefined config key: {}", key),
+        }
+        Ok(())
+    }
+
+    /// Process a `machine.<index>.*` key
+    fn process_machine(&mut self, key: &str, value: &'a str) {
+        let (index, field) = match indexed_key(key, MAX_MACHINES) {
+            Some(key) => key,
+            None => {
+                warn!("undefined config key: {}", key);
+                return;
+            }
+        };
+        if self.machines.len() <= index {
+            self.machines.resize(index + 1, MachineConfig::default());
+        }
+        match field {
+            "product" => self.machines[index].product = value,
+            "serial" => self.machines[index].serial = value,
+            "kernel_path" => self.machines[index].kernel_path = value,
+            _ => warn!("undefined config key: {}", key),
+        }
+    }
+}
+
+/// Split a key like `module.<index>.<field>` into the index, below `max`, and the field
+fn indexed_key(key: &str, max: usize) -> Option<(usize, &str)> {
+    let mut parts = key.splitn(3, '.').skip(1);
+    let index = usize::from_str(parts.next()?).ok()?;
+    let field = parts.next()?;
+    if index < max {
+        Some((index, field))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `s` as UTF-16 with a BOM, little or big endian
+    fn utf16(s: &str, big_endian: bool) -> Vec<u8> {
+        let to_bytes = if big_endian {
+            u16::to_be_bytes
+        } else {
+            u16::to_le_bytes
+        };
+        core::iter::once(0xFEFF)
+            .chain(s.encode_utf16())
+            .flat_map(to_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn decodes_utf8_without_bom() {
+        assert_eq!(decode(b"cmdline=a\n").unwrap(), "cmdline=a\n");
+        assert!(matches!(
+            decode(b"cmdline=\xff"),
+            Err(ConfigError::InvalidUtf8)
+        ));
+    }
+
+    #[test]
+    fn skips_utf8_bom() {
+        assert_eq!(decode(b"\xEF\xBB\xBFcmdline=a").unwrap(), "cmdline=a");
+    }
+
+    #[test]
+    fn decodes_utf16_with_bom() {
+        let text = "cmdline=\u{e9}\u{1F600}\r\n";
+        assert_eq!(decode(&utf16(text, false)).unwrap(), text);
+        assert_eq!(decode(&utf16(text, true)).unwrap(), text);
+    }
+
+    #[test]
+    fn refuses_bad_utf16() {
+        let mut odd = utf16("a=1", false);
+        odd.push(b'\n');
+        assert!(matches!(decode(&odd), Err(ConfigError::InvalidUtf8)));
+        // an unpaired high surrogate
+        let lone = [0xFF, 0xFE, 0x00, 0xD8, b'a', 0];
+        assert!(matches!(decode(&lone), Err(ConfigError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn parses_crlf_like_lf() {
+        let crlf =
+            Config::parse(b"kernel_path=\\k.elf\r\nkernel_stack_size=2\r\ncmdline=a b").unwrap();
+        let lf = Config::parse(b"kernel_path=\\k.elf\nkernel_stack_size=2\ncmdline=a b").unwrap();
+        assert_eq!(format!("{:?}", crlf), format!("{:?}", lf));
+        assert_eq!(crlf.kernel_path, "\\k.elf");
+        assert_eq!(crlf.kernel_stack_size, 2);
+        // the last line, without a line end
+        assert_eq!(crlf.cmdline, "a b");
+    }
+
+    #[test]
+    fn parses_final_line_end_alike() {
+        let with = Config::parse(b"cmdline=a\r\n").unwrap();
+        let without = Config::parse(b"cmdline=a").unwrap();
+        assert_eq!(format!("{:?}", with), format!("{:?}", without));
+        assert_eq!(with.cmdline, "a");
+    }
+
+    #[test]
+    fn takes_bss_fill_width_from_hex_digits() {
+        let width = |line: &str| {
+            let config = Config::parse(line.as_bytes()).unwrap();
+            match config.bss_init() {
+                BssInit::Fill { width, .. } => width,
+                bss => panic!("{:?} for {}", bss, line),
+            }
+        };
+        assert_eq!(width("bss_fill=0xDEADBEEF"), 4);
+        // leading zeros count, not only the set bytes
+        assert_eq!(width("bss_fill=0x00FF"), 2);
+        assert_eq!(width("bss_fill=0x0000_00FF"), 4);
+        assert_eq!(width("bss_fill=0x00DE_ADBE_EFDE_ADBE"), 8);
+        // rounded up to a power of two
+        assert_eq!(width("bss_fill=0xABCDEF"), 4);
+        assert_eq!(width("bss_fill=0xF"), 1);
+        assert_eq!(width("bss_fill=255"), 8);
+    }
+
+    #[test]
+    fn parses_int() {
+        assert_eq!(parse_int("0"), Some(0));
+        assert_eq!(parse_int("1234"), Some(1234));
+        assert_eq!(parse_int("0x1f"), Some(0x1f));
+        assert_eq!(parse_int("0xDEAD_BEEF"), Some(0xDEAD_BEEF));
+        assert_eq!(parse_int("1_000_000"), Some(1_000_000));
+        assert_eq!(parse_int("18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_int("0xffff_ffff_ffff_ffff"), Some(u64::MAX));
+    }
+
+    #[test]
+    fn refuses_bad_int() {
+        assert_eq!(parse_int(""), None);
+        assert_eq!(parse_int("_"), None);
+        assert_eq!(parse_int("0x_"), None);
+        assert_eq!(parse_int("18446744073709551616"), None);
+        assert_eq!(parse_int("0x1_0000_0000_0000_0000"), None);
+        assert_eq!(parse_int("12a"), None);
+        assert_eq!(parse_int("0xfg"), None);
+        assert_eq!(parse_int("-1"), None);
+        assert_eq!(parse_int(" 1"), None);
+    }
+
+    #[test]
+    fn refuses_invalid_utf8() {
+        assert!(matches!(
+            Config::parse(b"cmdline=\xc3"),
+            Err(ConfigError::InvalidUtf8)
+        ));
+    }
+
+    #[test]
+    fn refuses_missing_value() {
+        assert!(matches!(
+            Config::parse(b"# comment\n\nzero_bss"),
+            Err(ConfigError::MissingValue { line: 3 })
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_integer() {
+        assert!(matches!(
+            Config::parse(b"kernel_stack_size=0x"),
+            Err(ConfigError::BadInteger {
+                line: 1,
+                key: "kernel_stack_size",
+                value: "0x"
+            })
+        ));
+        // in range of u64, but not of the key
+        assert!(matches!(
+            Config::parse(b"file_open_retries=0x1_0000_0000"),
+            Err(ConfigError::BadInteger {
+                key: "file_open_retries",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_boolean() {
+        assert!(matches!(
+            Config::parse(b"zero_bss=yes"),
+            Err(ConfigError::BadBoolean {
+                line: 1,
+                key: "zero_bss",
+                value: "yes"
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_value() {
+        assert!(matches!(
+            Config::parse(b"kernel_compression=gzip"),
+            Err(ConfigError::BadValue {
+                line: 1,
+                key: "kernel_compression",
+                value: "gzip"
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_resolution() {
+        for value in ["1024", "1024x", "x768", "1024x0x1_0000_0000"] {
+            let line = format!("resolution={}", value);
+            assert!(matches!(
+                Config::parse(line.as_bytes()),
+                Err(ConfigError::BadResolution { line: 1, value: v }) if v == value
+            ));
+        }
+    }
+
+    fn validate(content: &'static [u8]) -> Result<(), ConfigError<'static>> {
+        Config::parse(content)?.validate()
+    }
+
+    #[test]
+    fn validates_default_config() {
+        assert!(validate(b"").is_ok());
+    }
+
+    #[test]
+    fn refuses_zero_stack() {
+        assert!(matches!(
+            validate(b"kernel_stack_size=0"),
+            Err(ConfigError::BadStackSize)
+        ));
+        assert!(matches!(
+            validate(b"kernel_stack_gap=0"),
+            Err(ConfigError::BadStackGap)
+        ));
+        assert!(matches!(
+            validate(b"lazy_stack_pages=0"),
+            Err(ConfigError::BadLazyStackPages)
+        ));
+    }
+
+    #[test]
+    fn refuses_misaligned_phys_offset() {
+        assert!(validate(b"physical_memory_offset=0xFFFF_8000_0000_0000").is_ok());
+        assert!(matches!(
+            validate(b"physical_memory_offset=0xFFFF_8000_0010_0000"),
+            Err(ConfigError::MisalignedPhysOffset)
+        ));
+    }
+
+    #[test]
+    fn refuses_ab_boot_without_fallback() {
+        assert!(matches!(
+            validate(b"ab_boot=on"),
+            Err(ConfigError::MissingFallbackKernel)
+        ));
+        assert!(validate(b"ab_boot=on\nfallback_kernel_path=\\b.elf").is_ok());
+    }
+
+    #[test]
+    fn refuses_missing_module_path() {
+        assert!(matches!(
+            validate(b"module.1.path=\\b"),
+            Err(ConfigError::MissingModulePath(0))
+        ));
+        assert!(matches!(
+            validate(b"module.0.path=\\a\nmodule.1.cmdline=x"),
+            Err(ConfigError::MissingModulePath(1))
+        ));
+    }
+
+    #[test]
+    fn refuses_incomplete_machine() {
+        assert!(matches!(
+            validate(b"machine.0.product=p"),
+            Err(ConfigError::IncompleteMachine(0))
+        ));
+        assert!(matches!(
+            validate(b"machine.0.product=p\nmachine.0.kernel_path=\\a\nmachine.1.kernel_path=\\b"),
+            Err(ConfigError::IncompleteMachine(1))
+        ));
+        assert!(validate(b"machine.0.serial=s\nmachine.0.kernel_path=\\a").is_ok());
+    }
+
+    #[test]
+    fn refuses_too_many_kaslr_bits() {
+        let line = format!("kaslr_bits={}", MAX_KASLR_BITS);
+        assert!(Config::parse(line.as_bytes()).unwrap().validate().is_ok());
+        let line = format!("kaslr_bits={}", MAX_KASLR_BITS + 1);
+        assert!(matches!(
+            Config::parse(line.as_bytes()).unwrap().validate(),
+            Err(ConfigError::KaslrBitsTooLarge)
+        ));
+    }
+
+    #[test]
+    fn refuses_undefined_register_bits() {
+        assert!(validate(b"cr4_set=0x80\nefer_clear=0x800").is_ok());
+        assert!(matches!(
+            validate(b"cr0_clear=0x100"),
+            Err(ConfigError::BadRegisterMask("cr0_clear"))
+        ));
+        assert!(matches!(
+            validate(b"efer_set=0x2"),
+            Err(ConfigError::BadRegisterMask("efer_set"))
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_force_usable() {
+        assert!(validate(b"force_usable=0x1000:0x2000").is_ok());
+        for content in [
+            &b"force_usable=0x1000:0"[..],
+            b"force_usable=0x1800:0x1000",
+            b"force_usable=0x1000:0x800",
+        ] {
+            assert!(matches!(
+                validate(content),
+                Err(ConfigError::BadForceUsable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn collects_module_lines() {
+        let config = Config::parse(b"module=\\a.img\nmodule=\\b.dtb").unwrap();
+        let paths: Vec<_> = config.modules.iter().map(|m| (m.path, m.cmdline)).collect();
+        assert_eq!(paths, [("\\a.img", ""), ("\\b.dtb", "")]);
+        let config =
+            Config::parse(b"module.1.path=\\b\nmodule.0.path=\\a\nmodule.0.cmdline=x").unwrap();
+        let paths: Vec<_> = config.modules.iter().map(|m| (m.path, m.cmdline)).collect();
+        assert_eq!(paths, [("\\a", "x"), ("\\b", "")]);
+    }
+
+    #[test]
+    fn refuses_mixed_module_forms() {
+        assert!(matches!(
+            Config::parse(b"module=\\a\nmodule.0.path=\\b"),
+            Err(ConfigError::MixedModuleForms { line: 2 })
+        ));
+        assert!(matches!(
+            Config::parse(b"module.0.cmdline=x\nmodule=\\a"),
+            Err(ConfigError::MixedModuleForms { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn refuses_too_many_modules() {
+        let lines = "module=\\m\n".repeat(MAX_MODULES);
+        assert_eq!(
+            Config::parse(lines.as_bytes()).unwrap().modules.len(),
+            MAX_MODULES
+        );
+        let lines = lines + "module=\\m";
+        assert!(matches!(
+            Config::parse(lines.as_bytes()),
+            Err(ConfigError::TooManyModules { line }) if line == MAX_MODULES + 1
+        ));
+        let line = format!("module.{}.path=\\m", MAX_MODULES);
+        assert!(matches!(
+            Config::parse(line.as_bytes()),
+            Err(ConfigError::TooManyModules { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn defaults_to_nonempty_direct_map_window() {
+        let config = Config::parse(b"direct_map=minimal").unwrap();
+        assert_eq!(config.direct_map_window, 0x10_0000);
+        let config = Config::parse(b"direct_map=minimal\ndirect_map_window=0").unwrap();
+        assert_eq!(config.direct_map_window, 0);
+    }
+
+    #[test]
+    fn lists_origins_in_order() {
+        let mut config = Config::parse(b"# kernel\nkernel_path=\\a\ncmdline=x").unwrap();
+        config.set_origin("cmdline", Origin::LoadOptions);
+        config.set_origin("kernel_path", Origin::Machine(1));
+        config.set_origin("kernel_path", Origin::Variable("BootAttempts"));
+        let origins: Vec<_> = config
+            .origins()
+            .filter(|&(_, origin)| origin != Origin::Build)
+            .collect();
+        assert_eq!(
+            origins,
+            [
+                ("kernel_path", Origin::Line(2)),
+                ("cmdline", Origin::Line(3)),
+                ("cmdline", Origin::LoadOptions),
+                ("kernel_path", Origin::Machine(1)),
+                ("kernel_path", Origin::Variable("BootAttempts")),
+            ]
+        );
     }
 }
@@ -0,0 +1,34 @@
+//! CRC-32 (IEEE 802.3), as computed by `crc32` and gzip
+
+/// The reflected polynomial
+const POLY: u32 = 0xEDB8_8320;
+
+/// Lookup table for one byte at a time
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ crc >> 8
+    })
+}
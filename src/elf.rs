@@ -0,0 +1,298 @@
+//! Inspection of the kernel ELF file
+
+use core::convert::TryInto;
+use core::mem::size_of;
+use rboot::{
+    GraphicNote, GRAPHIC_NOTE_MAGIC, NOTE_NAME, NOTE_SECTION, NOTE_TYPE_GRAPHIC,
+    NOTE_TYPE_LAZY_STACK,
+};
+use xmas_elf::header::{self, Class, Data, Machine};
+use xmas_elf::sections::{SectionData, SHN_ABS};
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::{program, ElfFile};
+
+/// The section of the GNU build ID note, as written by `ld --build-id`
+const BUILD_ID_SECTION: &str = ".note.gnu.build-id";
+/// The owner name of GNU notes
+const GNU_NOTE_NAME: &[u8] = b"GNU\0";
+/// The type of the GNU build ID note
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Read the `u64` at virtual address `addr` from the file contents of LOAD segments
+pub fn read_u64(elf: &ElfFile, addr: u64) -> Option<u64> {
+    let offset = file_offset(elf, addr, 8)?;
+    let bytes = elf.input.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// The file offset of the `len` bytes at virtual address `addr`,
+/// if they are within the file contents of a LOAD segment
+pub fn file_offset(elf: &ElfFile, addr: u64, len: u64) -> Option<usize> {
+    let segment = elf.program_iter().find(|segment| {
+        segment.get_type() == Ok(program::Type::Load)
+            && addr >= segment.virtual_addr()
+            && addr + len <= segment.virtual_addr() + segment.file_size()
+    })?;
+    Some((segment.offset() + addr - segment.virtual_addr()) as usize)
+}
+
+/// The end of the highest LOAD segment in memory, or 0 if there is none
+pub fn image_end(elf: &ElfFile) -> u64 {
+    elf.program_iter()
+        .filter(|segment| segment.get_type() == Ok(program::Type::Load))
+        .map(|segment| segment.virtual_addr() + segment.mem_size())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Check that `elf` is a little-endian x86_64 executable or shared object with
+/// LOAD segments, whose entry point is in one, which rboot can map and jump to
+pub fn validate_elf(elf: &ElfFile) -> Result<(), &'static str> {
+    if elf.header.pt1.magic != header::MAGIC {
+        return Err("not an ELF file");
+    }
+    if elf.header.pt1.class() != Class::SixtyFour {
+        return Err("not a 64-bit ELF file");
+    }
+    if elf.header.pt1.data() != Data::LittleEndian {
+        return Err("not a little-endian ELF file");
+    }
+    if elf.header.pt2.machine().as_machine() != Machine::X86_64 {
+        return Err("not built for x86_64");
+    }
+    match elf.header.pt2.type_().as_type() {
+        header::Type::Executable | header::Type::SharedObject => {}
+        _ => return Err("not an executable or shared object"),
+    }
+    let has_load = elf
+        .program_iter()
+        .any(|segment| segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0);
+    if !has_load {
+        return Err("the ELF has no loadable segments");
+    }
+    let entry = elf.header.pt2.entry_point();
+    if entry == 0 {
+        return Err("the entry point is 0");
+    }
+    let in_segment = elf.program_iter().any(|segment| {
+        segment.get_type() == Ok(program::Type::Load)
+            && entry >= segment.virtual_addr()
+            && entry - segment.virtual_addr() < segment.mem_size()
+    });
+    if !in_segment {
+        return Err("the entry point is not in a LOAD segment");
+    }
+    Ok(())
+}
+
+/// Read the first `u64` of the first LOAD segment, where a kernel may place a magic value
+pub fn magic(elf: &ElfFile) -> Option<u64> {
+    let segment = elf
+        .program_iter()
+        .find(|segment| segment.get_type() == Ok(program::Type::Load))?;
+    read_u64(elf, segment.virtual_addr())
+}
+
+/// Get the value of the `u64` symbol `name`.
+///
+/// For an absolute symbol this is the symbol value itself,
+/// otherwise the `u64` stored at the symbol's address.
+pub fn symbol_u64(elf: &ElfFile, name: &str) -> Option<u64> {
+    let symbols = match elf.find_section_by_name(".symtab")?.get_data(elf) {
+        Ok(SectionData::SymbolTable64(symbols)) => symbols,
+        _ => return None,
+    };
+    let symbol = symbols
+        .iter()
+        .find(|symbol| symbol.get_name(elf) == Ok(name))?;
+    if symbol.shndx() == SHN_ABS {
+        Some(symbol.value())
+    } else {
+        read_u64(elf, symbol.value())
+    }
+}
+
+/// Get the graphic mode preferred by the kernel, from its `.note.rboot` section
+pub fn graphic_note(elf: &ElfFile) -> Option<GraphicNote> {
+    let desc = notes(elf, NOTE_TYPE_GRAPHIC).find(|desc| {
+        desc.len() >= size_of::<GraphicNote>() && read_u32(desc, 0) == GRAPHIC_NOTE_MAGIC
+    })?;
+    Some(GraphicNote {
+        magic: read_u32(desc, 0),
+        width: read_u32(desc, 4),
+        height: read_u32(desc, 8),
+        format: read_u32(desc, 12),
+    })
+}
+
+/// Whether the kernel declares in a note that it handles page faults
+/// on its stack from the start, as required by `lazy_stack`
+pub fn lazy_stack_note(elf: &ElfFile) -> bool {
+    notes(elf, NOTE_TYPE_LAZY_STACK).next().is_some()
+}
+
+/// The build ID of the kernel, from its GNU build ID note
+pub fn build_id<'a>(elf: &ElfFile<'a>) -> Option<&'a [u8]> {
+    section_notes(elf, BUILD_ID_SECTION, GNU_NOTE_NAME, NT_GNU_BUILD_ID).next()
+}
+
+/// The descriptors of the notes for rboot of type `ty`
+fn notes<'a>(elf: &ElfFile<'a>, ty: u32) -> impl Iterator<Item = &'a [u8]> {
+    section_notes(elf, NOTE_SECTION, NOTE_NAME, ty)
+}
+
+/// The descriptors of the notes of owner `name` and type `ty` in `section`
+fn section_notes<'a>(
+    elf: &ElfFile<'a>,
+    section: &str,
+    name: &'static [u8],
+    ty: u32,
+) -> impl Iterator<Item = &'a [u8]> {
+    let mut notes = match elf.find_section_by_name(section) {
+        Some(section) => section.raw_data(elf),
+        None => &[],
+    };
+    // each note is a header of name size, descriptor size and type,
+    // followed by the name and the descriptor, both padded to 4 bytes
+    core::iter::from_fn(move || {
+        while notes.len() >= 12 {
+            let name_size = read_u32(notes, 0) as usize;
+            let desc_size = read_u32(notes, 4) as usize;
+            let note_ty = read_u32(notes, 8);
+            let desc_start = 12 + (name_size + 3) / 4 * 4;
+            let desc_end = desc_start + (desc_size + 3) / 4 * 4;
+            if notes.len() < desc_end {
+                break;
+            }
+            let note = notes;
+            notes = &notes[desc_end..];
+            if &note[12..12 + name_size] == name && note_ty == ty {
+                return Some(&note[desc_start..desc_start + desc_size]);
+            }
+        }
+        None
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The largest alignment of LOAD segments, at least 4KiB.
+///
+/// Warns about segments whose virtual address and file offset differ modulo
+/// their alignment, since aligning the file can not align them.
+pub fn max_align(elf: &ElfFile) -> u64 {
+    elf.program_iter()
+        .filter(|segment| segment.get_type() == Ok(program::Type::Load))
+        .map(|segment| {
+            let align = segment.align().max(1);
+            if segment.virtual_addr() % align != segment.offset() % align {
+                warn!(
+                    "segment at {:#x} is not aligned like its file offset",
+                    segment.virtual_addr()
+                );
+            }
+            align
+        })
+        .filter(|align| align.is_power_of_two())
+        .fold(0x1000, u64::max)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const PT_LOAD: u32 = 1;
+    const PT_NOTE: u32 = 4;
+    const PT_GNU_STACK: u32 = 0x6474_e551;
+
+    /// A program header, for `build_elf`
+    #[derive(Clone, Copy, Default)]
+    pub(crate) struct Phdr {
+        pub ty: u32,
+        pub flags: u32,
+        pub offset: u64,
+        pub vaddr: u64,
+        pub filesz: u64,
+        pub memsz: u64,
+        pub align: u64,
+    }
+
+    /// An x86_64 executable with `phdrs` and `len` bytes in all, as `u64`s for
+    /// the alignment `ElfFile` requires. See `bytes`.
+    pub(crate) fn build_elf(entry: u64, phdrs: &[Phdr], len: usize) -> Vec<u64> {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        file.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        file.extend_from_slice(&0x3eu16.to_le_bytes()); // EM_X86_64
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&entry.to_le_bytes());
+        file.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        file.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        file.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64, 56, phdrs.len() as u16, 64, 0, 0] {
+            file.extend_from_slice(&u16::to_le_bytes(half));
+        }
+        for phdr in phdrs {
+            file.extend_from_slice(&phdr.ty.to_le_bytes());
+            file.extend_from_slice(&phdr.flags.to_le_bytes());
+            for word in [
+                phdr.offset,
+                phdr.vaddr,
+                phdr.vaddr,
+                phdr.filesz,
+                phdr.memsz,
+                phdr.align,
+            ] {
+                file.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        file.resize(file.len().max(len), 0);
+        file.chunks(8)
+            .map(|word| {
+                let mut bytes = [0; 8];
+                bytes[..word.len()].copy_from_slice(word);
+                u64::from_le_bytes(bytes)
+            })
+            .collect()
+    }
+
+    /// The bytes of a file built by `build_elf`
+    pub(crate) fn bytes(file: &[u64]) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(file.as_ptr() as *const u8, file.len() * 8) }
+    }
+
+    #[test]
+    fn accepts_load_segment() {
+        let load = Phdr {
+            ty: PT_LOAD,
+            vaddr: 0xFFFF_FFFF_8000_0000,
+            memsz: 0x1000,
+            align: 0x1000,
+            ..Phdr::default()
+        };
+        let file = build_elf(0xFFFF_FFFF_8000_0000, &[load], 0);
+        let elf = ElfFile::new(bytes(&file)).unwrap();
+        assert_eq!(validate_elf(&elf), Ok(()));
+    }
+
+    #[test]
+    fn refuses_no_load_segments() {
+        let note = Phdr {
+            ty: PT_NOTE,
+            offset: 0x100,
+            filesz: 0x10,
+            align: 4,
+            ..Phdr::default()
+        };
+        let stack = Phdr {
+            ty: PT_GNU_STACK,
+            flags: 6,
+            ..Phdr::default()
+        };
+        let file = build_elf(0xFFFF_FFFF_8000_0000, &[note, stack], 0x110);
+        let elf = ElfFile::new(bytes(&file)).unwrap();
+        assert_eq!(validate_elf(&elf), Err("the ELF has no loadable segments"));
+    }
+}
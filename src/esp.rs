@@ -0,0 +1,139 @@
+//! Discovery of the EFI system partition from GPT partition tables
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::convert::TryInto;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::{BootServices, SearchType};
+
+use crate::crc32::crc32;
+
+/// Partition type GUID of the ESP (C12A7328-F81F-11D2-BA4B-00A0C93EC93B), in GPT byte order
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// The size of the GPT header up to the partition entry array CRC
+const MIN_HEADER_SIZE: usize = 92;
+/// The largest partition entry array read, the 128 entries of 128 bytes the spec
+/// sizes it for, so that a corrupt header does not cause a huge allocation
+const MAX_ENTRIES_SIZE: usize = 128 * 128;
+
+/// Find the file system of an ESP by scanning the GPT of every disk.
+///
+/// The partition handle is matched to the GPT entry by comparing its first block
+/// with the block at the entry's start LBA on the disk.
+pub fn find_esp(bs: &BootServices) -> Option<&UnsafeCell<SimpleFileSystem>> {
+    info!("searching for ESP");
+    let handles = bs
+        .locate_handle_buffer(SearchType::from_proto::<BlockIO>())
+        .ok()?;
+    let handles = handles.handles();
+    let block_io = |handle| {
+        bs.handle_protocol::<BlockIO>(handle)
+            .ok()
+            .map(|io| unsafe { &*io.get() })
+    };
+    for disk in handles.iter().filter_map(|&handle| block_io(handle)) {
+        if disk.media().is_logical_partition() {
+            continue;
+        }
+        let block_size = disk.media().block_size() as usize;
+        for start_lba in esp_start_lbas(disk) {
+            let mut expected = vec![0u8; block_size];
+            if disk
+                .read_blocks(disk.media().media_id(), start_lba, &mut expected)
+                .is_err()
+            {
+                continue;
+            }
+            for &handle in handles {
+                let partition = match block_io(handle) {
+                    Some(io) if io.media().is_logical_partition() => io,
+                    _ => continue,
+                };
+                if partition.media().block_size() as usize != block_size {
+                    continue;
+                }
+                let mut first = vec![0u8; block_size];
+                if partition
+                    .read_blocks(partition.media().media_id(), 0, &mut first)
+                    .is_err()
+                    || first != expected
+                {
+                    continue;
+                }
+                if let Ok(fs) = bs.handle_protocol::<SimpleFileSystem>(handle) {
+                    info!("found ESP at LBA {:#x}", start_lba);
+                    return Some(fs);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Start LBAs of the ESP entries in the GPT of `disk`, none if it has no valid GPT.
+///
+/// The header and the partition entry array are checked against their CRCs.
+/// Entry arrays larger than `MAX_ENTRIES_SIZE` are refused.
+fn esp_start_lbas(disk: &BlockIO) -> Vec<u64> {
+    let media = disk.media();
+    let block_size = media.block_size() as usize;
+    if block_size < MIN_HEADER_SIZE {
+        return Vec::new();
+    }
+    let mut header = vec![0u8; block_size];
+    if disk.read_blocks(media.media_id(), 1, &mut header).is_err() || &header[..8] != b"EFI PART" {
+        return Vec::new();
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let header_size = u32_at(12) as usize;
+    if !(MIN_HEADER_SIZE..=block_size).contains(&header_size) {
+        warn!("GPT header has a bad size {:#x}", header_size);
+        return Vec::new();
+    }
+    // the CRC is computed with its own field zeroed
+    let header_crc = u32_at(16);
+    let mut zeroed = header[..header_size].to_vec();
+    zeroed[16..20].fill(0);
+    if crc32(&zeroed) != header_crc {
+        warn!("GPT header has a bad CRC");
+        return Vec::new();
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32_at(80) as usize;
+    let entry_size = u32_at(84) as usize;
+    let entries_crc = u32_at(88);
+    if entry_size < 128 || !entry_size.is_power_of_two() {
+        warn!("GPT has a bad partition entry size {:#x}", entry_size);
+        return Vec::new();
+    }
+    let size = match entry_count.checked_mul(entry_size) {
+        Some(size) if size <= MAX_ENTRIES_SIZE => size,
+        _ => {
+            warn!(
+                "GPT has too many partition entries: {} of {:#x} bytes",
+                entry_count, entry_size
+            );
+            return Vec::new();
+        }
+    };
+    let mut entries = vec![0u8; (size + block_size - 1) / block_size * block_size];
+    if disk
+        .read_blocks(media.media_id(), entries_lba, &mut entries)
+        .is_err()
+    {
+        return Vec::new();
+    }
+    if crc32(&entries[..size]) != entries_crc {
+        warn!("GPT partition entries have a bad CRC");
+        return Vec::new();
+    }
+    entries[..size]
+        .chunks(entry_size)
+        .filter(|entry| entry[..16] == ESP_TYPE_GUID)
+        .map(|entry| u64::from_le_bytes(entry[32..40].try_into().unwrap()))
+        .collect()
+}
@@ -0,0 +1,317 @@
+//! Read files from a FAT12/16/32 file system image in memory
+//!
+//! Only lookup by path and reading of whole files are supported.
+//! Long file names are matched as well as 8.3 names, both case insensitively.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+/// Size of a directory entry
+const DIR_ENTRY_SIZE: usize = 32;
+/// Attribute of long file name entries
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+
+/// Error of a malformed image
+#[derive(Debug)]
+pub enum FatError {
+    /// The boot sector has no valid BIOS parameter block
+    BadBootSector,
+    /// The image is smaller than its boot sector says
+    Truncated,
+    /// The FAT is too small to hold an entry for every cluster
+    FatTooSmall,
+    /// The cluster chain of a file ends before its size
+    ShortChain,
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::BadBootSector => write!(f, "bad boot sector"),
+            FatError::Truncated => write!(f, "image is truncated"),
+            FatError::FatTooSmall => write!(f, "FAT is too small for the clusters"),
+            FatError::ShortChain => write!(f, "cluster chain is shorter than the file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where the entries of a directory are
+#[derive(Clone, Copy)]
+enum Dir {
+    /// The fixed root directory of FAT12/16, as a byte range
+    Fixed(usize, usize),
+    /// A cluster chain starting at the cluster
+    Chain(u32),
+}
+
+/// A file found in a volume
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    cluster: u32,
+    /// The size in bytes
+    pub size: usize,
+}
+
+/// A FAT volume, read from its image bytes
+pub struct Volume<'a> {
+    data: &'a [u8],
+    ty: FatType,
+    cluster_size: usize,
+    /// Byte offset of the first FAT
+    fat_start: usize,
+    /// Byte offset of cluster 2
+    data_start: usize,
+    /// Number of data clusters
+    clusters: u32,
+    root: Dir,
+}
+
+impl<'a> Volume<'a> {
+    /// Parse the boot sector of the image in `data`
+    pub fn new(data: &'a [u8]) -> Result<Self, FatError> {
+        if data.len() < 512 || data[510..512] != [0x55, 0xAA] {
+            return Err(FatError::BadBootSector);
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]) as usize;
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let sector_size = u16_at(11);
+        let sectors_per_cluster = data[13] as usize;
+        let reserved_sectors = u16_at(14);
+        let fats = data[16] as usize;
+        let root_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32) as usize,
+            n => n,
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36) as usize,
+            n => n,
+        };
+        if !sector_size.is_power_of_two()
+            || sector_size < 512
+            || sectors_per_cluster == 0
+            || fats == 0
+        {
+            return Err(FatError::BadBootSector);
+        }
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE + sector_size - 1) / sector_size;
+        let root_start = reserved_sectors + fats * fat_sectors;
+        let data_sector = root_start + root_sectors;
+        let clusters = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(FatError::BadBootSector)?
+            / sectors_per_cluster;
+        if total_sectors * sector_size > data.len() {
+            return Err(FatError::Truncated);
+        }
+        // the type is defined by the number of clusters only
+        let ty = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        // `next_cluster` reads the entries of clusters up to the last one
+        let last = clusters + 1;
+        let fat_size = match ty {
+            FatType::Fat12 => last + last / 2 + 2,
+            FatType::Fat16 => (last + 1) * 2,
+            FatType::Fat32 => (last + 1) * 4,
+        };
+        if fat_size > fat_sectors * sector_size {
+            return Err(FatError::FatTooSmall);
+        }
+        let root = match ty {
+            FatType::Fat32 => Dir::Chain(u32_at(44)),
+            _ => Dir::Fixed(root_start * sector_size, root_entries * DIR_ENTRY_SIZE),
+        };
+        Ok(Volume {
+            data,
+            ty,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_start: reserved_sectors * sector_size,
+            data_start: data_sector * sector_size,
+            clusters: clusters as u32,
+            root,
+        })
+    }
+
+    /// Find the file at `path`. Both `/` and `\` separate components.
+    pub fn find(&self, path: &str) -> Option<File> {
+        let mut dir = self.root;
+        let mut components = path.split(&['\\', '/'][..]).filter(|c| !c.is_empty());
+        let mut component = components.next()?;
+        loop {
+            let (file, is_dir) = self.find_entry(dir, component)?;
+            match components.next() {
+                Some(next) if is_dir => {
+                    dir = Dir::Chain(file.cluster);
+                    component = next;
+                }
+                None if !is_dir => return Some(file),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Read the whole `file` into the start of `buf`
+    pub fn read(&self, file: File, buf: &mut [u8]) -> Result<(), FatError> {
+        let mut offset = 0;
+        for cluster in self.chain(file.cluster) {
+            if offset >= file.size {
+                break;
+            }
+            let len = self.cluster_size.min(file.size - offset);
+            buf[offset..offset + len].copy_from_slice(&self.cluster(cluster)[..len]);
+            offset += len;
+        }
+        if offset < file.size {
+            return Err(FatError::ShortChain);
+        }
+        Ok(())
+    }
+
+    /// Find the entry named `name` in `dir`, and whether it is a directory
+    fn find_entry(&self, dir: Dir, name: &str) -> Option<(File, bool)> {
+        let entries: Vec<&[u8]> = match dir {
+            Dir::Fixed(start, len) => self.data[start..start + len]
+                .chunks(DIR_ENTRY_SIZE)
+                .collect(),
+            Dir::Chain(cluster) => self
+                .chain(cluster)
+                .flat_map(|cluster| self.cluster(cluster).chunks(DIR_ENTRY_SIZE))
+                .collect(),
+        };
+        // the long name of the next short entry, in pieces of 13 characters
+        let mut long_name: Vec<u16> = Vec::new();
+        for entry in entries {
+            match entry[0] {
+                0 => break,
+                0xE5 => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            let attr = entry[11];
+            if attr == ATTR_LONG_NAME {
+                let index = ((entry[0] & 0x1F) as usize).saturating_sub(1);
+                if long_name.len() < (index + 1) * 13 {
+                    long_name.resize((index + 1) * 13, 0xFFFF);
+                }
+                let chars = entry[1..11]
+                    .chunks(2)
+                    .chain(entry[14..26].chunks(2))
+                    .chain(entry[28..32].chunks(2))
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]));
+                for (i, c) in chars.enumerate() {
+                    long_name[index * 13 + i] = c;
+                }
+                continue;
+            }
+            let long = core::mem::take(&mut long_name);
+            if attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let long_matches =
+                matches!(long_name_of(&long), Some(long) if long.eq_ignore_ascii_case(name));
+            if long_matches || short_name_of(entry).eq_ignore_ascii_case(name) {
+                let cluster = (u16::from_le_bytes([entry[20], entry[21]]) as u32) << 16
+                    | u16::from_le_bytes([entry[26], entry[27]]) as u32;
+                let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+                return Some((File { cluster, size }, attr & ATTR_DIRECTORY != 0));
+            }
+        }
+        None
+    }
+
+    /// The clusters of the chain starting at `cluster`
+    fn chain(&self, cluster: u32) -> impl Iterator<Item = u32> + '_ {
+        let mut next = Some(cluster);
+        // stop at free or bad entries, or a loop
+        core::iter::from_fn(move || {
+            let cluster = next.filter(|&c| c >= 2 && c < self.clusters + 2)?;
+            next = self.next_cluster(cluster);
+            Some(cluster)
+        })
+        .take(self.clusters as usize)
+    }
+
+    /// The next cluster after `cluster` in the FAT, or none at the end of a chain.
+    /// The FAT holds an entry for `cluster`, as checked by `new`.
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let n = cluster as usize;
+        let fat = &self.data[self.fat_start..];
+        let (next, end) = match self.ty {
+            FatType::Fat12 => {
+                let offset = n + n / 2;
+                let value = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+                let value = if n % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xFFF
+                };
+                (value as u32, 0xFF8)
+            }
+            FatType::Fat16 => (
+                u16::from_le_bytes([fat[n * 2], fat[n * 2 + 1]]) as u32,
+                0xFFF8,
+            ),
+            FatType::Fat32 => {
+                let value = u32::from_le_bytes(fat[n * 4..n * 4 + 4].try_into().unwrap());
+                (value & 0x0FFF_FFFF, 0x0FFF_FFF8)
+            }
+        };
+        if next >= end {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    /// The bytes of `cluster`
+    fn cluster(&self, cluster: u32) -> &'a [u8] {
+        let start = self.data_start + (cluster as usize - 2) * self.cluster_size;
+        &self.data[start..start + self.cluster_size]
+    }
+}
+
+/// Decode a long file name, which ends at a NUL or at padding
+fn long_name_of(chars: &[u16]) -> Option<String> {
+    if chars.is_empty() {
+        return None;
+    }
+    let len = chars
+        .iter()
+        .position(|&c| c == 0 || c == 0xFFFF)
+        .unwrap_or(chars.len());
+    char::decode_utf16(chars[..len].iter().copied())
+        .collect::<Result<String, _>>()
+        .ok()
+}
+
+/// The 8.3 name of `entry`, as `NAME.EXT`
+fn short_name_of(entry: &[u8]) -> String {
+    let trim = |bytes: &[u8]| {
+        let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    };
+    let mut name = trim(&entry[0..8]);
+    let ext = trim(&entry[8..11]);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
@@ -0,0 +1,152 @@
+//! Drawing text on the framebuffer with a built-in font
+//!
+//! The font has 8x16 glyphs for printable ASCII, `0x20` to `0x7E`, in the style
+//! of the VGA font. Each glyph is 16 bytes, one per row from the top, whose most
+//! significant bit is the leftmost pixel.
+
+use rboot::GraphicInfo;
+use uefi::proto::console::gop::PixelFormat;
+
+pub(crate) const GLYPH_WIDTH: usize = 8;
+pub(crate) const GLYPH_HEIGHT: usize = 16;
+
+const FONT: &[u8; 95 * GLYPH_HEIGHT] = include_bytes!("font8x16.bin");
+
+/// A color as red, green and blue
+pub(crate) type Color = (u8, u8, u8);
+
+/// A framebuffer with 32-bit RGB or BGR pixels
+pub(crate) struct Framebuffer<'a> {
+    /// Scan lines of `stride` pixels, of which the first `width` are shown
+    pixels: &'a mut [u32],
+    width: usize,
+    /// Pixels per scan line
+    stride: usize,
+    bgr: bool,
+}
+
+impl<'a> Framebuffer<'a> {
+    /// The framebuffer of `graphic_info`, or none if its pixels are neither RGB nor BGR
+    pub(crate) fn new(graphic_info: &GraphicInfo) -> Option<Framebuffer<'static>> {
+        let mode = &graphic_info.mode;
+        let bgr = match mode.pixel_format() {
+            PixelFormat::Rgb => false,
+            PixelFormat::Bgr => true,
+            _ => return None,
+        };
+        let (width, height) = mode.resolution();
+        // the framebuffer may be smaller than the mode says
+        let height = height.min(graphic_info.fb_size as usize / 4 / mode.stride());
+        let pixels = unsafe {
+            core::slice::from_raw_parts_mut(
+                graphic_info.fb_addr as *mut u32,
+                height * mode.stride(),
+            )
+        };
+        Some(Framebuffer::from_pixels(pixels, width, mode.stride(), bgr))
+    }
+
+    /// The framebuffer drawing into `pixels`, scan lines of `stride` pixels of
+    /// which the first `width` are shown
+    pub(crate) fn from_pixels(
+        pixels: &'a mut [u32],
+        width: usize,
+        stride: usize,
+        bgr: bool,
+    ) -> Self {
+        Framebuffer {
+            pixels,
+            width: width.min(stride),
+            stride,
+            bgr,
+        }
+    }
+
+    fn pixel(&self, (r, g, b): Color) -> u32 {
+        if self.bgr {
+            u32::from_le_bytes([b, g, r, 0])
+        } else {
+            u32::from_le_bytes([r, g, b, 0])
+        }
+    }
+}
+
+/// Draw `c` in `fg` on `bg` with its top left corner at pixel (`x`, `y`),
+/// clipped to the framebuffer. Characters other than printable ASCII are drawn as `?`.
+pub(crate) fn draw_char(fb: &mut Framebuffer, x: usize, y: usize, c: char, fg: Color, bg: Color) {
+    let c = if (' '..='~').contains(&c) { c } else { '?' };
+    let index = (c as usize - 0x20) * GLYPH_HEIGHT;
+    let glyph = &FONT[index..index + GLYPH_HEIGHT];
+    let (fg, bg) = (fb.pixel(fg), fb.pixel(bg));
+    for (row, &bits) in glyph.iter().enumerate() {
+        // whole scan lines only, so that each has `width` pixels
+        let line = match fb.pixels.chunks_exact_mut(fb.stride).nth(y + row) {
+            Some(line) => &mut line[..fb.width],
+            None => break,
+        };
+        for (column, pixel) in line.iter_mut().skip(x).take(GLYPH_WIDTH).enumerate() {
+            *pixel = if bits & (0x80 >> column) != 0 { fg } else { bg };
+        }
+    }
+}
+
+/// Draw `s` in a line starting at pixel (`x`, `y`), like `draw_char`
+pub(crate) fn draw_str(fb: &mut Framebuffer, x: usize, y: usize, s: &str, fg: Color, bg: Color) {
+    for (i, c) in s.chars().enumerate() {
+        draw_char(fb, x + i * GLYPH_WIDTH, y, c, fg, bg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 12;
+    const STRIDE: usize = 16;
+    const SENTINEL: u32 = 0xdead_beef;
+
+    /// Draw `c` at (`x`, `y`) in a 12x20 framebuffer, white on red
+    fn draw(c: char, x: usize, y: usize, bgr: bool) -> [u32; STRIDE * 20] {
+        let mut pixels = [SENTINEL; STRIDE * 20];
+        let mut fb = Framebuffer::from_pixels(&mut pixels, WIDTH, STRIDE, bgr);
+        draw_char(&mut fb, x, y, c, (0xff, 0xff, 0xff), (0xff, 0, 0));
+        pixels
+    }
+
+    #[test]
+    fn draws_glyph_bits() {
+        let pixels = draw('A', 1, 2, false);
+        let glyph = &FONT[(b'A' - 0x20) as usize * GLYPH_HEIGHT..][..GLYPH_HEIGHT];
+        for (index, &pixel) in pixels.iter().enumerate() {
+            let (x, y) = (index % STRIDE, index / STRIDE);
+            let expected = match (x.checked_sub(1), y.checked_sub(2)) {
+                (Some(column), Some(row)) if column < GLYPH_WIDTH && row < GLYPH_HEIGHT => {
+                    if glyph[row] & (0x80 >> column) != 0 {
+                        0x00ff_ffff
+                    } else {
+                        0x0000_00ff
+                    }
+                }
+                _ => SENTINEL,
+            };
+            assert_eq!(pixel, expected, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn orders_bgr_channels() {
+        // the background of ' ' fills the whole glyph
+        assert_eq!(draw(' ', 0, 0, false)[0], 0x0000_00ff);
+        assert_eq!(draw(' ', 0, 0, true)[0], 0x00ff_0000);
+    }
+
+    #[test]
+    fn clips_to_width_and_height() {
+        let pixels = draw(' ', WIDTH - 2, 20 - 3, false);
+        for (index, &pixel) in pixels.iter().enumerate() {
+            let (x, y) = (index % STRIDE, index / STRIDE);
+            let inside = (WIDTH - 2..WIDTH).contains(&x) && y >= 20 - 3;
+            assert_eq!(pixel != SENTINEL, inside, "pixel ({}, {})", x, y);
+        }
+    }
+}
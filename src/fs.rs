@@ -0,0 +1,476 @@
+//! Loading files from the boot volume, a bundle or a boot image
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::*;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::*;
+use uefi::{CStr16, Event};
+use xmas_elf::ElfFile;
+
+use crate::{elf, esp, fat, loadfile, lz4, tar, CONFIG_PATH};
+
+/// The config member of a bundle, which replaces the config at `CONFIG_PATH`
+const BUNDLE_CONFIG: &str = "rboot.conf";
+
+/// The size of each read in `load_file`
+const READ_CHUNK_SIZE: usize = 0x10_0000;
+
+/// The watchdog code of a read in `load_file` which never returned,
+/// codes up to 0xffff are reserved for the firmware
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// The delay before the first retry of opening a file, in microseconds,
+/// which doubles with each further retry
+const RETRY_DELAY: usize = 100_000;
+
+/// How many times opening a file is retried, from `file_open_retries`
+static OPEN_RETRIES: AtomicU32 = AtomicU32::new(3);
+
+/// Set how many times opening a file is retried after a failure
+/// other than the file not existing
+pub(crate) fn set_open_retries(retries: u32) {
+    OPEN_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Open the root directory of the volume rboot was loaded from.
+/// Fall back to the first available file system if that device has none,
+/// and to searching the GPT of every disk for an ESP if there is none at all.
+fn open_root(bs: &BootServices, image: Handle) -> uefi::Result<Directory> {
+    let device = boot_device(bs, image);
+    let fs = bs
+        .handle_protocol::<SimpleFileSystem>(device)
+        .or_else(|_| {
+            warn!("boot device has no FileSystem, using the first one found");
+            bs.locate_protocol::<SimpleFileSystem>()
+        })
+        .or_else(|e| esp::find_esp(bs).ok_or(e))
+        .expect("failed to get FileSystem");
+    let fs = unsafe { &mut *fs.get() };
+    fs.open_volume()
+}
+
+/// The device rboot was loaded from
+pub(crate) fn boot_device(bs: &BootServices, image: Handle) -> Handle {
+    let loaded_image = bs
+        .handle_protocol::<LoadedImage>(image)
+        .expect("failed to get LoadedImage");
+    unsafe { &*loaded_image.get() }.device()
+}
+
+/// Open regular file at `path`
+pub(crate) fn open_file(bs: &BootServices, image: Handle, path: &str) -> RegularFile {
+    match open(bs, image, path) {
+        FileType::Regular(regular) => regular,
+        FileType::Dir(_) => panic!("failed to open {}: {}", path, LoadError::Directory),
+    }
+}
+
+/// Open directory at `path`
+pub(crate) fn open_dir(bs: &BootServices, image: Handle, path: &str) -> Directory {
+    match open(bs, image, path) {
+        FileType::Dir(dir) => dir,
+        _ => panic!("{} is not a directory", path),
+    }
+}
+
+/// Names of the regular files in `dir`
+pub(crate) fn read_dir(dir: &mut Directory) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut info_buf = [0u8; 0x400];
+    while let Some(info) = dir
+        .read_entry(&mut info_buf)
+        .expect("failed to read directory entry")
+    {
+        if !info.attribute().contains(FileAttribute::DIRECTORY) {
+            names.push(format!("{}", info.file_name()));
+        }
+    }
+    names
+}
+
+/// Open file or directory at `path`
+pub(crate) fn open(bs: &BootServices, image: Handle, path: &str) -> FileType {
+    try_open(bs, image, path).unwrap_or_else(|e| panic!("failed to open {}: {}", path, e))
+}
+
+/// Open file or directory at `path`, failing if it does not exist
+pub(crate) fn try_open(
+    bs: &BootServices,
+    image: Handle,
+    path: &str,
+) -> Result<FileType, LoadError> {
+    info!("opening file: {}", path);
+    // FIXME: convert `str` to `CStr16` without a fixed buf.
+    let mut buf = [0u16; 256];
+    let path16 =
+        CStr16::from_str_with_buf(path, &mut buf).expect("failed to convert path to ucs-2");
+    // removable media may not be ready yet
+    let retries = OPEN_RETRIES.load(Ordering::Relaxed);
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let result = open_root(bs, image)
+            .and_then(|mut root| root.open(path16, FileMode::Read, FileAttribute::empty()));
+        match result {
+            Ok(handle) => return Ok(handle.into_type().expect("failed to into_type")),
+            Err(e) if e.status() == Status::NOT_FOUND => return Err(LoadError::NotFound),
+            Err(e) if attempt < retries => {
+                debug!("failed to open {}: {:?}, retrying", path, e.status());
+                bs.stall(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!(
+                    "failed to open {} after {} attempts: {:?}",
+                    path,
+                    attempt + 1,
+                    e.status()
+                );
+                return Err(LoadError::Read(e.status()));
+            }
+        }
+    }
+}
+
+/// Write `data` to the file at `path` on the boot volume, replacing it
+pub(crate) fn write_file(
+    bs: &BootServices,
+    image: Handle,
+    path: &str,
+    data: &[u8],
+) -> Result<(), Status> {
+    let mut buf = [0u16; 256];
+    let path16 =
+        CStr16::from_str_with_buf(path, &mut buf).expect("failed to convert path to ucs-2");
+    let mut root = open_root(bs, image).map_err(|e| e.status())?;
+    // creating does not truncate an existing file
+    if let Ok(old) = root.open(path16, FileMode::ReadWrite, FileAttribute::empty()) {
+        old.delete().map_err(|e| e.status())?;
+    }
+    let handle = root
+        .open(path16, FileMode::CreateReadWrite, FileAttribute::empty())
+        .map_err(|e| e.status())?;
+    let mut file = match handle.into_type().map_err(|e| e.status())? {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return Err(Status::ACCESS_DENIED),
+    };
+    file.write(data).map_err(|e| e.status())?;
+    file.flush().map_err(|e| e.status())
+}
+
+/// Error of loading a file
+#[derive(Debug)]
+pub(crate) enum LoadError {
+    /// There is nothing at the path
+    NotFound,
+    /// The path is a directory
+    Directory,
+    /// The firmware failed to open or read the file
+    Read(Status),
+    /// A read made no progress within the given seconds
+    Stalled(u64),
+    /// The file is malformed in the boot image
+    BadImage(fat::FatError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "file not found"),
+            LoadError::Directory => write!(f, "is a directory, not a regular file"),
+            LoadError::Read(status) => write!(f, "read failed: {:?}", status),
+            LoadError::Stalled(timeout) => {
+                write!(f, "read stalled: no progress within {} seconds", timeout)
+            }
+            LoadError::BadImage(e) => write!(f, "malformed boot image: {}", e),
+        }
+    }
+}
+
+/// Where files are loaded from instead of the boot volume
+pub(crate) enum Source {
+    /// A tar archive
+    Bundle(tar::Archive<'static>),
+    /// A FAT file system image
+    Image(fat::Volume<'static>),
+}
+
+impl Source {
+    /// The path of the config, which replaces the one at `CONFIG_PATH`
+    pub(crate) fn config_path(&self) -> &'static str {
+        match self {
+            Source::Bundle(_) => BUNDLE_CONFIG,
+            Source::Image(_) => CONFIG_PATH,
+        }
+    }
+
+    pub(crate) fn contains(&self, path: &str) -> bool {
+        match self {
+            Source::Bundle(bundle) => bundle.find(path).is_some(),
+            Source::Image(volume) => volume.find(path).is_some(),
+        }
+    }
+}
+
+/// File contents at the start of the pages allocated for them
+pub(crate) struct Loaded {
+    pub buf: &'static mut [u8],
+    /// The number of pages allocated, which `buf` may not fill
+    pub pages: usize,
+}
+
+impl Loaded {
+    /// The pages of `buf`, as returned by `allocate_buf`, of which the first
+    /// `len` bytes are used
+    fn new(buf: &'static mut [u8], len: usize) -> Self {
+        Loaded {
+            pages: buf.len() / 0x1000,
+            buf: &mut buf[..len],
+        }
+    }
+
+    /// Free the pages
+    pub(crate) fn free(self, bs: &BootServices) {
+        bs.free_pages(self.buf.as_ptr() as u64, self.pages)
+            .expect("failed to free pages");
+    }
+}
+
+/// Load the file at `path`, from `source` if there is one
+pub(crate) fn load(
+    bs: &BootServices,
+    image: Handle,
+    source: Option<&Source>,
+    path: &str,
+    timeout: u64,
+) -> &'static mut [u8] {
+    load_at(bs, image, source, path, timeout, AllocateType::AnyPages)
+}
+
+/// Load the file at `path` like `load`, with the pages allocated for it,
+/// for freeing them later
+pub(crate) fn load_pages(
+    bs: &BootServices,
+    image: Handle,
+    source: Option<&Source>,
+    path: &str,
+    timeout: u64,
+) -> Loaded {
+    try_load_at(bs, image, source, path, timeout, AllocateType::AnyPages)
+        .unwrap_or_else(|e| panic!("failed to load {}: {}", path, e))
+}
+
+/// Load the file at `path` like `load`, to pages allocated as `placement`
+pub(crate) fn load_at(
+    bs: &BootServices,
+    image: Handle,
+    source: Option<&Source>,
+    path: &str,
+    timeout: u64,
+    placement: AllocateType,
+) -> &'static mut [u8] {
+    try_load_at(bs, image, source, path, timeout, placement)
+        .unwrap_or_else(|e| panic!("failed to load {}: {}", path, e))
+        .buf
+}
+
+/// Load the file at `path` like `load_at`, failing if it can not be read
+pub(crate) fn try_load_at(
+    bs: &BootServices,
+    image: Handle,
+    source: Option<&Source>,
+    path: &str,
+    timeout: u64,
+    placement: AllocateType,
+) -> Result<Loaded, LoadError> {
+    match source {
+        Some(Source::Image(volume)) => {
+            let file = volume.find(path).ok_or(LoadError::NotFound)?;
+            info!("reading {} from boot image", path);
+            let loaded = Loaded::new(allocate_buf(bs, file.size, placement), file.size);
+            volume.read(file, loaded.buf).map_err(LoadError::BadImage)?;
+            Ok(loaded)
+        }
+        Some(Source::Bundle(bundle)) => {
+            let member = bundle.find(path).ok_or(LoadError::NotFound)?;
+            info!("extracting {} from bundle", member.name);
+            // copy to page aligned memory
+            let buf = allocate_buf(bs, member.data.len(), placement);
+            let loaded = Loaded::new(buf, member.data.len());
+            loaded.buf.copy_from_slice(member.data);
+            Ok(loaded)
+        }
+        None => {
+            // without a file system on the boot device, try its LoadFile2
+            let device = boot_device(bs, image);
+            if bs.handle_protocol::<SimpleFileSystem>(device).is_err() {
+                match loadfile::load(bs, device, path, placement) {
+                    Some(Ok(buf)) => return Ok(buf),
+                    Some(Err(status)) if status != Status::NOT_FOUND => {
+                        return Err(LoadError::Read(status))
+                    }
+                    // fall back to the file system
+                    Some(Err(_)) => debug!("{} not found with LoadFile2", path),
+                    None => {}
+                }
+            }
+            match try_open(bs, image, path)? {
+                FileType::Regular(mut file) => load_file(bs, &mut file, timeout, placement),
+                FileType::Dir(_) => Err(LoadError::Directory),
+            }
+        }
+    }
+}
+
+/// Decompress the LZ4 frames in `compressed` to new pages, and free its pages
+pub(crate) fn decompress_lz4(bs: &BootServices, compressed: Loaded) -> Loaded {
+    let size =
+        lz4::decompressed_size(compressed.buf).unwrap_or_else(|e| panic!("malformed LZ4: {}", e));
+    info!("decompressing LZ4 to {:#x} bytes", size);
+    let out = allocate_buf(bs, size, AllocateType::AnyPages);
+    let len =
+        lz4::decompress(compressed.buf, out).unwrap_or_else(|e| panic!("malformed LZ4: {}", e));
+    compressed.free(bs);
+    Loaded::new(out, len)
+}
+
+/// Move the ELF `loaded` to memory aligned to the largest alignment of its
+/// segments, freeing its pages. Segments are mapped from the file contents,
+/// so their frames are then aligned like their virtual addresses.
+pub(crate) fn align_elf(bs: &BootServices, loaded: Loaded) -> Loaded {
+    let align = elf::max_align(&ElfFile::new(loaded.buf).expect("failed to parse ELF"));
+    if loaded.buf.as_ptr() as u64 % align == 0 {
+        return loaded;
+    }
+    info!("aligning kernel to {:#x}", align);
+    let pages = loaded.buf.len() / 0x1000 + 1;
+    // over-allocate, and free the padding around the aligned part
+    let padding = (align / 0x1000) as usize - 1;
+    let start = bs
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            pages + padding,
+        )
+        .expect("failed to allocate pages");
+    let aligned = x86_64::align_up(start, align);
+    let head = ((aligned - start) / 0x1000) as usize;
+    if head != 0 {
+        bs.free_pages(start, head).expect("failed to free pages");
+    }
+    if padding > head {
+        bs.free_pages(aligned + pages as u64 * 0x1000, padding - head)
+            .expect("failed to free pages");
+    }
+    let len = loaded.buf.len();
+    let new_buf = unsafe { core::slice::from_raw_parts_mut(aligned as *mut u8, pages * 0x1000) };
+    new_buf[..len].copy_from_slice(loaded.buf);
+    loaded.free(bs);
+    Loaded::new(new_buf, len)
+}
+
+/// Allocate pages for at least `size` bytes, as `placement`
+pub(crate) fn allocate_buf(
+    bs: &BootServices,
+    size: usize,
+    placement: AllocateType,
+) -> &'static mut [u8] {
+    let pages = size / 0x1000 + 1;
+    let mem_start = bs
+        .allocate_pages(placement, MemoryType::LOADER_DATA, pages)
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to allocate {} pages {:x?}: {:?}",
+                pages, placement, e
+            )
+        });
+    unsafe { core::slice::from_raw_parts_mut(mem_start as *mut u8, pages * 0x1000) }
+}
+
+/// Load file to new pages allocated as `placement`
+///
+/// The file is read in chunks. If `timeout` is nonzero and a chunk takes
+/// longer than `timeout` seconds to read, loading is aborted.
+/// Reads are synchronous, so a read which never returns is caught by the
+/// firmware watchdog instead, which resets the machine after twice `timeout`.
+/// The watchdog is disabled once the file is read.
+pub(crate) fn load_file(
+    bs: &BootServices,
+    file: &mut RegularFile,
+    timeout: u64,
+    placement: AllocateType,
+) -> Result<Loaded, LoadError> {
+    info!("loading file to memory");
+    let mut info_buf = [0u8; 0x100];
+    let info = file
+        .get_info::<FileInfo>(&mut info_buf)
+        .map_err(|e| LoadError::Read(e.status()))?;
+    let buf = allocate_buf(bs, info.file_size() as usize, placement);
+
+    let timer = if timeout != 0 {
+        let event = unsafe { bs.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) }
+            .expect("failed to create timer event");
+        Some(event)
+    } else {
+        None
+    };
+    let result = read_chunks(bs, file, buf, timeout, timer.as_ref());
+    if let Some(timer) = timer {
+        bs.close_event(timer).expect("failed to close timer event");
+        // firmware without a watchdog can not catch a hung read anyway
+        bs.set_watchdog_timer(0, WATCHDOG_CODE, None).ok();
+    }
+    match result {
+        Ok(len) => Ok(Loaded::new(buf, len)),
+        Err(e) => {
+            bs.free_pages(buf.as_ptr() as u64, buf.len() / 0x1000)
+                .expect("failed to free pages");
+            Err(e)
+        }
+    }
+}
+
+/// Read `file` into `buf` in chunks, each within `timeout` seconds of `timer`
+/// if there is one. Return the number of bytes read.
+fn read_chunks(
+    bs: &BootServices,
+    file: &mut RegularFile,
+    buf: &mut [u8],
+    timeout: u64,
+    timer: Option<&Event>,
+) -> Result<usize, LoadError> {
+    let mut len = 0;
+    loop {
+        if let Some(timer) = timer {
+            // in 100ns units
+            bs.set_timer(timer, TimerTrigger::Relative(timeout * 10_000_000))
+                .expect("failed to set timer");
+            // fires even if the read never returns,
+            // later than the timer so that a slow read is reported first
+            bs.set_watchdog_timer(2 * timeout as usize, WATCHDOG_CODE, None)
+                .ok();
+        }
+        let end = (len + READ_CHUNK_SIZE).min(buf.len());
+        let read = file
+            .read(&mut buf[len..end])
+            .map_err(|e| LoadError::Read(e.status()))?;
+        if let Some(timer) = timer {
+            let expired = bs
+                .check_event(unsafe { timer.unsafe_clone() })
+                .expect("failed to check timer");
+            if expired {
+                return Err(LoadError::Stalled(timeout));
+            }
+        }
+        if read == 0 {
+            return Ok(len);
+        }
+        len += read;
+    }
+}
@@ -0,0 +1,277 @@
+//! Graphic output setup and framebuffer helpers
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rboot::{EarlyConsole, GraphicInfo, GraphicMode};
+use uefi::prelude::*;
+use uefi::proto::console::gop::{GraphicsOutput, ModeInfo, PixelFormat};
+use uefi::proto::console::text::Output;
+use uefi::table::boot::*;
+
+use crate::fbcon;
+
+/// No graphic mode to restore
+const NO_MODE: u32 = u32::MAX;
+
+/// The graphic mode before `init_graphic` switched it, for `restore_mode`
+static ORIGINAL_MODE: AtomicU32 = AtomicU32::new(NO_MODE);
+
+/// If `resolution` or `pixel_format` is some, then set graphic mode matching them.
+/// Return information of the final graphic mode, or none if there is no graphic output.
+pub(crate) fn init_graphic(
+    bs: &BootServices,
+    resolution: Option<(usize, usize)>,
+    pixel_format: Option<PixelFormat>,
+) -> Option<GraphicInfo> {
+    let gop = match bs.locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => gop,
+        Err(_) => {
+            warn!("no GraphicsOutput, booting without framebuffer");
+            return None;
+        }
+    };
+    let gop = unsafe { &mut *gop.get() };
+
+    let wanted = |info: &ModeInfo| {
+        resolution
+            .iter()
+            .all(|&resolution| info.resolution() == resolution)
+            && pixel_format
+                .iter()
+                .all(|&format| info.pixel_format() == format)
+    };
+    // A loader which started rboot may have already set the graphic mode,
+    // in which case it is kept as it is.
+    let mode = if resolution.is_none() && pixel_format.is_none() || wanted(&gop.current_mode_info())
+    {
+        None
+    } else {
+        let mode = gop
+            .modes()
+            .enumerate()
+            .find(|(_, mode)| wanted(mode.info()));
+        if mode.is_none() {
+            warn!("graphic mode not found, keeping the current one");
+        }
+        mode
+    };
+    let mode_number = if let Some((number, mode)) = mode {
+        info!("switching graphic mode");
+        ORIGINAL_MODE.store(
+            current_mode_number(gop).unwrap_or(NO_MODE),
+            Ordering::Relaxed,
+        );
+        gop.set_mode(&mode).expect("Failed to set graphics mode");
+        Some(number as u32)
+    } else {
+        current_mode_number(gop)
+    };
+    Some(GraphicInfo {
+        mode: gop.current_mode_info(),
+        fb_addr: gop.frame_buffer().as_mut_ptr() as u64,
+        fb_size: gop.frame_buffer().size() as u64,
+        mode_number,
+    })
+}
+
+/// The graphic modes of the firmware, only the first one of those with the same
+/// resolution and pixel format, up to `max` of them
+pub(crate) fn available_modes(bs: &BootServices, max: usize) -> Vec<GraphicMode> {
+    let mut modes: Vec<GraphicMode> = Vec::new();
+    let gop = match bs.locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => unsafe { &mut *gop.get() },
+        Err(_) => return modes,
+    };
+    for (number, mode) in gop.modes().enumerate() {
+        let info = *mode.info();
+        let duplicate = modes.iter().any(|m| {
+            m.mode.resolution() == info.resolution() && m.mode.pixel_format() == info.pixel_format()
+        });
+        if duplicate {
+            continue;
+        }
+        if modes.len() == max {
+            warn!("more than {} graphic modes, passing only those", max);
+            break;
+        }
+        modes.push(GraphicMode {
+            mode: info,
+            mode_number: number as u32,
+        });
+    }
+    modes
+}
+
+/// Set the graphic mode back to the one before `init_graphic` switched it, if any,
+/// so that error messages of a failed boot are shown on the firmware console.
+/// Does nothing after `keep_mode`.
+#[cfg(not(test))]
+pub(crate) fn restore_mode() {
+    let number = ORIGINAL_MODE.swap(NO_MODE, Ordering::Relaxed);
+    if number == NO_MODE {
+        return;
+    }
+    // boot services are still active, as checked above
+    let st = unsafe { uefi_services::system_table().as_mut() };
+    let gop = match st.boot_services().locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => unsafe { &mut *gop.get() },
+        Err(_) => return,
+    };
+    let mode = match gop.modes().nth(number as usize) {
+        Some(mode) => mode,
+        None => return,
+    };
+    if gop.set_mode(&mode).is_ok() {
+        // the console may still be laid out for the switched mode
+        let _ = st.stdout().reset(false);
+    }
+}
+
+/// Keep the current graphic mode on failures from now on, which is required
+/// before exiting boot services.
+pub(crate) fn keep_mode() {
+    ORIGINAL_MODE.store(NO_MODE, Ordering::Relaxed);
+}
+
+/// Clear the text console, and the framebuffer with it.
+/// Setting a graphic mode in `init_graphic` later clears the framebuffer again.
+pub(crate) fn clear_screen(stdout: &mut Output) {
+    stdout.clear().expect("failed to clear console");
+}
+
+/// The size and cursor position of the text console, for the kernel to continue
+/// printing below rboot's messages
+pub(crate) fn early_console(stdout: &Output) -> Option<EarlyConsole> {
+    let mode = stdout.current_mode().ok().flatten()?;
+    let (column, row) = stdout.cursor_position();
+    Some(EarlyConsole {
+        columns: mode.columns() as u32,
+        rows: mode.rows() as u32,
+        column: column as u32,
+        row: row as u32,
+    })
+}
+
+/// Fill the framebuffer with vertical color bars, to check at a glance
+/// that `fb_addr` and the mode reported to the kernel are right.
+pub(crate) fn draw_test_pattern(graphic_info: &GraphicInfo) {
+    // white, yellow, cyan, green, magenta, red, blue, black
+    const BARS: [(u8, u8, u8); 8] = [
+        (255, 255, 255),
+        (255, 255, 0),
+        (0, 255, 255),
+        (0, 255, 0),
+        (255, 0, 255),
+        (255, 0, 0),
+        (0, 0, 255),
+        (0, 0, 0),
+    ];
+    let mode = &graphic_info.mode;
+    // scale an 8-bit channel into `mask`
+    let channel = |value: u8, mask: u32| match mask {
+        0 => 0,
+        _ => {
+            let shift = mask.trailing_zeros();
+            ((value as u32 * (mask >> shift) / 255) << shift) & mask
+        }
+    };
+    let pixel = |(r, g, b): (u8, u8, u8)| match mode.pixel_format() {
+        PixelFormat::Rgb => Some(u32::from_le_bytes([r, g, b, 0])),
+        PixelFormat::Bgr => Some(u32::from_le_bytes([b, g, r, 0])),
+        PixelFormat::Bitmask => mode
+            .pixel_bitmask()
+            .map(|mask| channel(r, mask.red) | channel(g, mask.green) | channel(b, mask.blue)),
+        PixelFormat::BltOnly => None,
+    };
+    let colors = match BARS
+        .iter()
+        .map(|&bar| pixel(bar))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(colors) => colors,
+        None => {
+            warn!("framebuffer can not be accessed directly, skipping test pattern");
+            return;
+        }
+    };
+    let (width, height) = mode.resolution();
+    let fb = graphic_info.fb_addr as *mut u32;
+    let fb_pixels = graphic_info.fb_size as usize / 4;
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * mode.stride() + x;
+            if index < fb_pixels {
+                let color = colors[x * colors.len() / width];
+                unsafe { fb.add(index).write_volatile(color) };
+            }
+        }
+    }
+    // label the bars with the mode, to compare with what the kernel reports
+    if let Some(mut fb) = fbcon::Framebuffer::new(graphic_info) {
+        let label = format!(
+            "{}x{} {:?} stride {}",
+            width,
+            height,
+            mode.pixel_format(),
+            mode.stride()
+        );
+        fbcon::draw_str(&mut fb, 0, 0, &label, (255, 255, 255), (0, 0, 0));
+    }
+}
+
+/// Allocate the parts of the framebuffer which the firmware reports as free
+/// memory as reserved, so that the kernel does not reuse them.
+pub(crate) fn reserve_framebuffer(
+    bs: &BootServices,
+    mmap: &[&MemoryDescriptor],
+    graphic_info: &GraphicInfo,
+) {
+    let start = x86_64::align_down(graphic_info.fb_addr, 0x1000);
+    let end = x86_64::align_up(graphic_info.fb_addr + graphic_info.fb_size, 0x1000);
+    for desc in mmap.iter().filter(|m| m.ty == MemoryType::CONVENTIONAL) {
+        let free_start = desc.phys_start.max(start);
+        let free_end = (desc.phys_start + desc.page_count * 0x1000).min(end);
+        if free_start >= free_end {
+            continue;
+        }
+        warn!(
+            "framebuffer range {:#x}..{:#x} is free memory, reserving it",
+            free_start, free_end
+        );
+        let pages = ((free_end - free_start) / 0x1000) as usize;
+        if let Err(e) = bs.allocate_pages(
+            AllocateType::Address(free_start as _),
+            MemoryType::RESERVED,
+            pages,
+        ) {
+            warn!("failed to reserve framebuffer: {:?}", e);
+        }
+    }
+}
+
+/// The start of `EFI_GRAPHICS_OUTPUT_PROTOCOL`
+#[repr(C)]
+struct RawGraphicsOutput {
+    query_mode: usize,
+    set_mode: usize,
+    blt: usize,
+    mode: *const RawMode,
+}
+
+/// The start of `EFI_GRAPHICS_OUTPUT_PROTOCOL_MODE`
+#[repr(C)]
+struct RawMode {
+    max_mode: u32,
+    mode: u32,
+}
+
+/// The number of the current graphic mode as the firmware reports it, which
+/// the `uefi` crate does not expose, or none if it is not a valid mode number.
+///
+/// Matching the current mode info against the modes would be ambiguous, as
+/// firmware may list the same mode more than once.
+fn current_mode_number(gop: &GraphicsOutput) -> Option<u32> {
+    let raw = gop as *const GraphicsOutput as *const RawGraphicsOutput;
+    let mode = unsafe { (*raw).mode.as_ref()? };
+    (mode.mode < mode.max_mode).then_some(mode.mode)
+}
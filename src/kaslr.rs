@@ -0,0 +1,104 @@
+//! Kernel address space layout randomization
+//!
+//! The kernel must be position independent (`ET_DYN`) and only have
+//! `R_X86_64_RELATIVE` dynamic relocations. It is slid by a multiple of 2MiB,
+//! so it keeps the alignment needed to map its text with huge pages.
+
+use alloc::vec::Vec;
+use x86_64::instructions::random::RdRand;
+use xmas_elf::header;
+use xmas_elf::program;
+use xmas_elf::sections::{SectionData, SHF_ALLOC};
+use xmas_elf::ElfFile;
+
+use crate::elf;
+
+/// The granularity of the slide
+pub const SLIDE_ALIGN: u64 = 0x20_0000;
+
+/// The last address of the lower canonical half
+const LOWER_HALF_END: u64 = 0x0000_7FFF_FFFF_FFFF;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Choose a random slide of up to `bits` bits in units of `SLIDE_ALIGN`.
+///
+/// The slid kernel stays canonical and in the half it was linked for.
+/// Returns 0 if the kernel is not position independent.
+pub fn choose_slide(elf: &ElfFile, bits: u8) -> u64 {
+    if elf.header.pt2.type_().as_type() != header::Type::SharedObject {
+        warn!("kernel is not position independent, KASLR disabled");
+        return 0;
+    }
+    let last = elf
+        .program_iter()
+        .filter(|segment| segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0)
+        .map(|segment| segment.virtual_addr() + segment.mem_size() - 1)
+        .max()
+        .unwrap_or(0);
+    let half_end = if last > LOWER_HALF_END {
+        u64::MAX
+    } else {
+        LOWER_HALF_END
+    };
+    let max_units = ((half_end - last) / SLIDE_ALIGN).min((1 << bits) - 1);
+    if max_units < (1 << bits) - 1 {
+        warn!("kernel only has room for {} slide positions", max_units + 1);
+    }
+    (random() % (max_units + 1)) * SLIDE_ALIGN
+}
+
+/// Apply the relocations of the kernel ELF in `buf` for `slide`
+pub fn relocate(buf: &mut [u8], slide: u64) {
+    let patches = {
+        let elf = ElfFile::new(buf).expect("failed to parse ELF");
+        let mut patches = Vec::new();
+        // only dynamic relocations, not those kept for sections by `--emit-relocs`
+        for section in elf
+            .section_iter()
+            .filter(|section| section.flags() & SHF_ALLOC != 0)
+        {
+            let relas = match section.get_data(&elf) {
+                Ok(SectionData::Rela64(relas)) => relas,
+                _ => continue,
+            };
+            for rela in relas {
+                match rela.get_type() {
+                    R_X86_64_NONE => {}
+                    R_X86_64_RELATIVE => {
+                        let offset =
+                            elf::file_offset(&elf, rela.get_offset(), 8).unwrap_or_else(|| {
+                                panic!(
+                                    "relocation at {:#x} is outside the file contents",
+                                    rela.get_offset()
+                                )
+                            });
+                        patches.push((offset, rela.get_addend().wrapping_add(slide)));
+                    }
+                    ty => panic!(
+                        "unsupported relocation type {} at {:#x}",
+                        ty,
+                        rela.get_offset()
+                    ),
+                }
+            }
+        }
+        patches
+    };
+    info!("applying {} relocations", patches.len());
+    for (offset, value) in patches {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// A random number from RDRAND, or from the time stamp counter without it
+fn random() -> u64 {
+    match RdRand::new().and_then(RdRand::get_u64) {
+        Some(value) => value,
+        None => {
+            warn!("RDRAND is not available, the slide is predictable");
+            unsafe { core::arch::x86_64::_rdtsc() }
+        }
+    }
+}
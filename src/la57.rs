@@ -0,0 +1,124 @@
+//! Switch to 5-level paging (LA57)
+//!
+//! CR4.LA57 can only be changed while paging is disabled, which is not possible
+//! in 64-bit mode. So the switch drops to 32-bit compatibility mode, disables
+//! paging, enables LA57 and paging with a 5-level table, and returns to 64-bit
+//! mode. Meanwhile the code, the GDT, the stack and the table are accessed by
+//! their 32-bit physical addresses, so they must all lie below 4GiB.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::mem::size_of_val;
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+use x86_64::instructions::{interrupts, tables::lgdt};
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Everything used during the switch must be below this address
+const LOW_MEMORY_END: u64 = 0x1_0000_0000;
+
+/// The GDT used for the switch: null, 64-bit code (0x08),
+/// 32-bit code (0x10) and data (0x18).
+///
+/// The accessed bits are preset, so the CPU does not write to it.
+static GDT: [u64; 4] = [
+    0,
+    0x00AF_9B00_0000_FFFF,
+    0x00CF_9B00_0000_FFFF,
+    0x00CF_9300_0000_FFFF,
+];
+
+/// Check whether the CPU supports 5-level paging, and the switch can be done
+pub fn supported() -> bool {
+    #[allow(unused_unsafe)]
+    let features = unsafe { __cpuid_count(7, 0) };
+    if features.ecx & (1 << 16) == 0 {
+        warn!("5-level paging is not supported by the CPU");
+        return false;
+    }
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        warn!("5-level paging is already enabled by the firmware");
+        return false;
+    }
+    let stack = 0u8;
+    let addrs = [
+        switch as *const () as u64,
+        GDT.as_ptr() as u64,
+        &stack as *const u8 as u64,
+    ];
+    if addrs.iter().any(|&addr| addr >= LOW_MEMORY_END) {
+        warn!("bootloader is loaded above 4GiB, can not switch to 5-level paging");
+        return false;
+    }
+    true
+}
+
+/// Create a 5-level page table from the current 4-level one.
+///
+/// The 4-level table is referenced by both the first and the last entry,
+/// so that both canonical halves keep their mappings.
+pub fn build_table(bs: &BootServices) -> PhysFrame {
+    let addr = bs
+        .allocate_pages(
+            AllocateType::MaxAddress((LOW_MEMORY_END - 1) as _),
+            MemoryType::LOADER_DATA,
+            1,
+        )
+        .expect("failed to allocate 5-level page table");
+    let table = unsafe { &mut *(addr as *mut PageTable) };
+    table.zero();
+    let p4_frame = Cr3::read().0;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    table[0].set_frame(p4_frame, flags);
+    table[511].set_frame(p4_frame, flags);
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+/// Switch to 5-level paging with a table from `build_table`.
+///
+/// Must be called after exiting boot services, and only if `supported`.
+pub unsafe fn switch(p5_frame: PhysFrame) {
+    let gdt = DescriptorTablePointer {
+        limit: (size_of_val(&GDT) - 1) as u16,
+        base: VirtAddr::new(GDT.as_ptr() as u64),
+    };
+    interrupts::disable();
+    lgdt(&gdt);
+    asm!(
+        // far return to 32-bit compatibility mode
+        "push 0x10",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "lea rdx, [rip + 3f]",
+        "retfq",
+        ".code32",
+        "2:",
+        // disable paging, which leaves long mode
+        "mov eax, cr0",
+        "btr eax, 31",
+        "mov cr0, eax",
+        "mov eax, cr4",
+        "bts eax, 12",
+        "mov cr4, eax",
+        "mov cr3, ecx",
+        // enable paging, which enters long mode again
+        "mov eax, cr0",
+        "bts eax, 31",
+        "mov cr0, eax",
+        // far return to 64-bit mode
+        "push 0x08",
+        "push edx",
+        "retf",
+        ".code64",
+        "3:",
+        "mov ax, 0x18",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+        in("rcx") p5_frame.start_address().as_u64(),
+        out("rax") _,
+        out("rdx") _,
+    );
+}
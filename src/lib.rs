@@ -11,21 +11,307 @@ pub use uefi::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryType};
 #[repr(C)]
 #[derive(Debug)]
 pub struct BootInfo {
+    /// The memory map at exit of boot services, sorted by ascending `phys_start`.
+    /// With `defer_exit_boot_services`, the one before the jump to the kernel,
+    /// which changes until the kernel exits boot services.
+    /// Ranges given by `force_usable` are reported as conventional memory.
     pub memory_map: Vec<&'static MemoryDescriptor>,
+    /// The size of the descriptors in the memory map of the firmware, which may be
+    /// larger than `MemoryDescriptor`, for striding over a raw copy of the map.
+    /// The descriptors in `memory_map` are referenced in place, except for
+    /// those added for `force_usable`.
+    pub memory_descriptor_size: u64,
+    /// The version of the descriptors in the memory map, as returned by the firmware.
+    /// 1 is the only version defined by the UEFI specification.
+    pub memory_descriptor_version: u32,
     /// The offset into the virtual address space where the physical memory is mapped.
     pub physical_memory_offset: u64,
-    /// The graphic output information
-    pub graphic_info: GraphicInfo,
+    /// The graphic output information, or none on headless machines without
+    /// graphic output, in which case the kernel should use a serial console.
+    ///
+    /// The framebuffer is mapped at `physical_memory_offset` with 4KiB pages,
+    /// non-executable, and write-combining if the CPU has a PAT: PAT entry 1,
+    /// selected by PWT alone, is programmed as write-combining instead of
+    /// write-through on the bootstrap processor, which the kernel should
+    /// program the same way on the other processors.
+    pub graphic_info: Option<GraphicInfo>,
+    /// The graphic modes of the firmware, one per resolution and pixel format,
+    /// up to `max_graphic_modes` of them. Empty without graphic output
+    pub available_graphic_modes: Vec<GraphicMode>,
+    /// Physical address of a zeroed buffer of `fb_size` bytes if `fb_backbuffer`
+    /// is set, for drawing off-screen, reserved in the memory map.
+    /// 0 if not set or without graphic output
+    pub fb_backbuffer_addr: u64,
+    /// The firmware text console on the framebuffer at the jump to the kernel,
+    /// if `early_console` is set and there is graphic output
+    pub early_console: Option<EarlyConsole>,
     /// Physical address of ACPI2 RSDP
     pub acpi2_rsdp_addr: u64,
-    /// Physical address of SMBIOS
+    /// Physical address of the XSDT, or of the RSDT if the firmware has no XSDT
+    pub xsdt_addr: u64,
+    /// Length of the XSDT (or RSDT) in bytes
+    pub xsdt_len: u32,
+    /// Physical address of the ACPI SRAT, or 0 if there is none
+    pub srat_addr: u64,
+    /// Length of the SRAT in bytes, or 0 if there is none
+    pub srat_len: u32,
+    /// The number of enabled processors in the ACPI MADT, or 1 if there is no MADT
+    pub cpu_count: u32,
+    /// The local APIC IDs of the enabled processors in the MADT, or empty if
+    /// there is no MADT. x2APIC IDs are included as they are
+    pub apic_ids: Vec<u32>,
+    /// Physical address of a free page below 1MiB if `ap_trampoline` is set,
+    /// identity mapped writable and executable, for the startup code of the
+    /// other processors, whose SIPI vector is this address shifted right by 12.
+    /// Otherwise 0
+    pub ap_trampoline_addr: u64,
+    /// Physical address of the 32-bit SMBIOS entry point, or of the 64-bit
+    /// (SMBIOS 3) one if the firmware only has that. 0 without SMBIOS
     pub smbios_addr: u64,
+    /// A copy of the SMBIOS entry point, the 64-bit (SMBIOS 3) one if present,
+    /// for reading the structure table it points to before mapping `smbios_addr`.
+    /// Empty if the entry point is invalid.
+    pub smbios_entry: &'static [u8],
     /// The start physical address of initramfs
     pub initramfs_addr: u64,
     /// The size of initramfs
     pub initramfs_size: u64,
+    /// The start physical address of the file at `kernel_symbols_path`, or 0
+    pub symbols_addr: u64,
+    /// The size of the file at `kernel_symbols_path`, or 0
+    pub symbols_size: u64,
     /// Kernel command line
     pub cmdline: &'static str,
+    /// Physical ranges of boot services code/data which are still mapped at
+    /// `physical_memory_offset`. Only filled if `keep_boot_services` is set.
+    /// The kernel may unmap them once it no longer calls into the firmware.
+    pub boot_services_ranges: Vec<MemoryRange>,
+    /// Physical address of the EFI system table.
+    /// Boot services and the console are unusable after exit, and the runtime services
+    /// and configuration table pointers are physical until `SetVirtualAddressMap`.
+    pub system_table_addr: u64,
+    /// The image handle of rboot if boot services are still active because of
+    /// `defer_exit_boot_services`, for the kernel to exit them with. Otherwise 0
+    pub image_handle: u64,
+    /// Firmware vendor, for firmware specific workarounds
+    pub firmware_vendor: &'static str,
+    /// Firmware revision, as defined by the vendor
+    pub firmware_revision: u32,
+    /// The time from `GetTime` of the runtime services, or none if it failed
+    pub boot_time: Option<BootTime>,
+    /// Files loaded from `module.<index>.path` in order, followed by those from `modules_dir`
+    pub modules: Vec<Module>,
+    /// The offset the kernel was loaded at from its linked addresses, a multiple
+    /// of 2MiB. 0 unless `kaslr_bits` is set
+    pub kernel_slide: u64,
+    /// How the kernel file was compressed, one of the `KERNEL_COMPRESSION_*` values
+    pub kernel_compression: u32,
+    /// The size of the kernel file as loaded
+    pub kernel_compressed_size: u64,
+    /// The size of the kernel ELF after decompressing, the file size if not compressed
+    pub kernel_decompressed_size: u64,
+    /// The GNU build ID of the kernel, from its `.note.gnu.build-id` section,
+    /// or empty if it has none
+    pub build_id: &'static [u8],
+    /// Physical range of the kernel file as loaded, whose LOAD segments are
+    /// mapped from it, except for their `.bss` part. With `identity_map_kernel`,
+    /// it is identity mapped until the kernel unmaps it.
+    pub kernel_phys_range: MemoryRange,
+    /// The lowest address of the kernel stack, whose top is in `rsp` at the entry.
+    /// With `stack_guard`, the page below it is unmapped
+    pub stack_bottom: u64,
+    /// The lowest mapped address of the kernel stack. Above `stack_bottom` if
+    /// `lazy_stack` is set, with the pages below it for the kernel to map on faults
+    pub stack_mapped_bottom: u64,
+    /// Physical ranges mapped at `physical_memory_offset`, sorted, starting 2MiB
+    /// aligned and ending 4KiB aligned.
+    /// All physical memory unless `direct_map=minimal` is set, in which case
+    /// the kernel maps the rest itself when it needs it.
+    pub direct_map_ranges: Vec<MemoryRange>,
+    /// The end of the highest physical range mapped at `physical_memory_offset`.
+    /// With `direct_map_clamp`, memory above it which did not fit is not mapped.
+    pub direct_map_max: u64,
+    /// The page tables rboot built for the kernel, per region
+    pub page_tables: PageTableSummary,
+    /// The config rboot booted with, if `pass_config` is set
+    pub config: Option<BootConfig>,
+}
+
+/// The config rboot booted with, for the kernel to log.
+/// Paths are empty if not set, and numbers are 0 if not set unless noted.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct BootConfig {
+    /// The path of the config file in use, within `bundle_path` or `boot_image` if set
+    pub config_path: &'static str,
+    /// The path of the kernel booted, which differs from `kernel_path` with
+    /// `chain_next` or `ab_boot`
+    pub kernel_path: &'static str,
+    pub kernel_symbols_path: &'static str,
+    pub initramfs: &'static str,
+    pub modules_dir: &'static str,
+    pub bundle_path: &'static str,
+    pub boot_image: &'static str,
+    /// The address of the kernel stack, also if placed by `kernel_stack_address=auto`
+    pub kernel_stack_address: u64,
+    pub kernel_stack_size: u64,
+    pub lazy_stack: bool,
+    pub stack_guard: bool,
+    pub physical_memory_offset: u64,
+    pub direct_map_minimal: bool,
+    pub direct_map_window: u64,
+    pub direct_map_exec: bool,
+    /// The `resolution` as width and height
+    pub resolution: (u32, u32),
+    pub initramfs_addr: u64,
+    pub initramfs_max_addr: u64,
+    pub zero_bss: bool,
+    pub bss_fill: u64,
+    pub preserve_phys_alignment: bool,
+    pub fresh_page_table: bool,
+    pub keep_boot_services: bool,
+    pub defer_exit_boot_services: bool,
+    pub pcid: bool,
+    pub five_level_paging: bool,
+    pub kaslr_bits: u32,
+    pub cr0_set: u64,
+    pub cr0_clear: u64,
+    pub cr4_set: u64,
+    pub cr4_clear: u64,
+    pub efer_set: u64,
+    pub efer_clear: u64,
+    pub boot_magic: u64,
+}
+
+/// Page tables rboot built for the kernel, per region.
+/// Frames of tables shared by regions are counted for the first one mapped.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct PageTableSummary {
+    pub kernel: PageTableRegion,
+    pub stack: PageTableRegion,
+    pub direct_map: PageTableRegion,
+}
+
+/// Page tables built for a region
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct PageTableRegion {
+    /// The number of frames allocated for page tables while mapping the region
+    pub table_frames: u64,
+    /// The largest page size the region is mapped with
+    pub page_size: u64,
+}
+
+/// The text console of the firmware, as rboot left it on the framebuffer.
+///
+/// The font and the placement of the text area are up to the firmware. EDK2 draws
+/// cells of 8x19 pixels, with the text area centered in the framebuffer.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct EarlyConsole {
+    /// The number of text columns of the console mode
+    pub columns: u32,
+    /// The number of text rows of the console mode
+    pub rows: u32,
+    /// The cursor column, where the next character goes
+    pub column: u32,
+    /// The cursor row
+    pub row: u32,
+}
+
+/// The wall clock time as read from the firmware before jumping to the kernel
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct BootTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    /// The offset from UTC in minutes, or `TIMEZONE_UNSPECIFIED` if the time is local
+    pub timezone: i16,
+    /// The EFI daylight flags, `0x1` to adjust for daylight saving time and
+    /// `0x2` while it is in effect
+    pub daylight: u8,
+}
+
+/// The `timezone` of a `BootTime` in local time with an unknown offset from UTC
+pub const TIMEZONE_UNSPECIFIED: i16 = 0x07FF;
+
+/// The section of the kernel ELF holding notes for rboot
+pub const NOTE_SECTION: &str = ".note.rboot";
+/// The owner name of notes for rboot
+pub const NOTE_NAME: &[u8] = b"rboot\0";
+/// The note type whose descriptor is a `GraphicNote`
+pub const NOTE_TYPE_GRAPHIC: u32 = 1;
+/// The note type which allows `lazy_stack`, with an empty descriptor.
+/// The kernel must handle page faults below `stack_mapped_bottom` from its entry
+pub const NOTE_TYPE_LAZY_STACK: u32 = 2;
+/// The magic number of `GraphicNote`
+pub const GRAPHIC_NOTE_MAGIC: u32 = 0x7262_6766;
+
+/// The graphic mode preferred by the kernel, declared in a note.
+/// The `resolution` in config overrides it.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct GraphicNote {
+    /// Must be `GRAPHIC_NOTE_MAGIC`
+    pub magic: u32,
+    /// Horizontal resolution, or 0 for any
+    pub width: u32,
+    /// Vertical resolution, or 0 for any
+    pub height: u32,
+    /// One of the `PIXEL_FORMAT_*` values
+    pub format: u32,
+}
+
+/// Any pixel format
+pub const PIXEL_FORMAT_ANY: u32 = 0;
+/// Red, green, blue and reserved bytes, in that order
+pub const PIXEL_FORMAT_RGB: u32 = 1;
+/// Blue, green, red and reserved bytes, in that order
+pub const PIXEL_FORMAT_BGR: u32 = 2;
+
+/// The kernel file was not compressed
+pub const KERNEL_COMPRESSION_NONE: u32 = 0;
+/// The kernel file was LZ4 frames
+pub const KERNEL_COMPRESSION_LZ4: u32 = 1;
+
+/// A file loaded for the kernel
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Module {
+    /// The start physical address
+    pub addr: u64,
+    /// The size in bytes
+    pub size: u64,
+    /// The file name
+    pub name: &'static str,
+    /// The command line from `module.<index>.cmdline`, empty for files from
+    /// `modules_dir` or a `module` line
+    pub cmdline: &'static str,
+}
+
+/// A range of physical memory `[start, end)`
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct MemoryRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// A graphic mode the firmware supports
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct GraphicMode {
+    pub mode: ModeInfo,
+    /// Graphic mode number, as used by `QueryMode`/`SetMode` of GOP
+    pub mode_number: u32,
 }
 
 /// Graphic output information
@@ -38,4 +324,7 @@ pub struct GraphicInfo {
     pub fb_addr: u64,
     /// Framebuffer size
     pub fb_size: u64,
+    /// Graphic mode number, as used by `QueryMode`/`SetMode` of GOP,
+    /// or none if the firmware reports an invalid one
+    pub mode_number: Option<u32>,
 }
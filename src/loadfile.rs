@@ -0,0 +1,95 @@
+//! Loading files through the `LoadFile2` protocol, for boot devices without file system
+//! (e.g. network boot)
+
+use alloc::vec::Vec;
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::unsafe_guid;
+
+use crate::fs::Loaded;
+
+/// The `EFI_LOAD_FILE2_PROTOCOL`
+#[repr(C)]
+#[unsafe_guid("4006c0c1-fcb3-403e-996d-4a6c8724e06d")]
+#[derive(Protocol)]
+pub struct LoadFile2 {
+    load_file: unsafe extern "efiapi" fn(
+        this: &mut LoadFile2,
+        file_path: *const u8,
+        boot_policy: bool,
+        buffer_size: &mut usize,
+        buffer: *mut u8,
+    ) -> Status,
+}
+
+/// Build a device path of a single file path node for `path`
+fn file_path(path: &str) -> Vec<u8> {
+    let mut node = Vec::new();
+    let name: Vec<u16> = path.encode_utf16().chain(Some(0)).collect();
+    let len = 4 + name.len() * 2;
+    // media device path, file path subtype
+    node.extend_from_slice(&[4, 4]);
+    node.extend_from_slice(&(len as u16).to_le_bytes());
+    for c in name {
+        node.extend_from_slice(&c.to_le_bytes());
+    }
+    // end of device path
+    node.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+    node
+}
+
+/// Load the file at `path` through the `LoadFile2` protocol of `device`
+/// to new pages allocated as `placement`. Return none if `device` has no such
+/// protocol, and the status of `LoadFile` if it fails, e.g. `NOT_FOUND`.
+pub fn load(
+    bs: &BootServices,
+    device: Handle,
+    path: &str,
+    placement: AllocateType,
+) -> Option<Result<Loaded, Status>> {
+    let load_file2 = bs.handle_protocol::<LoadFile2>(device).ok()?;
+    let load_file2 = unsafe { &mut *load_file2.get() };
+    info!("loading {} with LoadFile2", path);
+    let file_path = file_path(path);
+    // get the size first
+    let mut size = 0;
+    let status = unsafe {
+        (load_file2.load_file)(
+            load_file2,
+            file_path.as_ptr(),
+            false,
+            &mut size,
+            core::ptr::null_mut(),
+        )
+    };
+    if status != Status::BUFFER_TOO_SMALL {
+        return Some(Err(status));
+    }
+    let pages = size / 0x1000 + 1;
+    let mem_start = bs
+        .allocate_pages(placement, MemoryType::LOADER_DATA, pages)
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to allocate {} pages {:x?}: {:?}",
+                pages, placement, e
+            )
+        });
+    let status = unsafe {
+        (load_file2.load_file)(
+            load_file2,
+            file_path.as_ptr(),
+            false,
+            &mut size,
+            mem_start as *mut u8,
+        )
+    };
+    if !status.is_success() {
+        let _ = bs.free_pages(mem_start, pages);
+        return Some(Err(status));
+    }
+    Some(Ok(Loaded {
+        buf: unsafe { core::slice::from_raw_parts_mut(mem_start as *mut u8, size) },
+        pages,
+    }))
+}
@@ -0,0 +1,90 @@
+//! Capture the console output, for writing it to `log_file`
+//!
+//! The logger of `uefi_services` can not be replaced or wrapped, so the
+//! `OutputString` function of the console protocol is replaced instead,
+//! which sees every log record as it is printed. It may run in any boot
+//! services call which prints, so it does not allocate: the buffer is
+//! allocated up front, and output beyond it is dropped.
+
+use alloc::vec::Vec;
+use core::ptr::{addr_of_mut, replace};
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::*;
+use uefi::proto::console::text::Output;
+
+type OutputString = unsafe extern "efiapi" fn(*mut RawOutput, *const u16) -> Status;
+
+/// The start of `EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL`
+#[repr(C)]
+struct RawOutput {
+    reset: usize,
+    output_string: OutputString,
+}
+
+/// The most bytes captured, including `TRUNCATED_NOTE`
+const CAPACITY: usize = 1 << 20;
+/// Appended to the captured output if some was dropped
+const TRUNCATED_NOTE: &[u8] = b"\r\n[log truncated]\r\n";
+
+/// Whether `OutputString` is replaced
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+/// Whether output was dropped as the buffer was full
+static TRUNCATED: AtomicBool = AtomicBool::new(false);
+/// The `OutputString` of the firmware
+static mut ORIGINAL: Option<OutputString> = None;
+/// The captured output as UTF-8, with the `\r\n` line ends of the console
+static mut BUFFER: Vec<u8> = Vec::new();
+
+/// Start capturing everything written to `stdout`
+pub(crate) fn start(stdout: &mut Output) {
+    let raw = stdout as *mut Output as *mut RawOutput;
+    unsafe {
+        if !CAPTURING.swap(true, Ordering::Relaxed) {
+            BUFFER = Vec::with_capacity(CAPACITY);
+            ORIGINAL = Some((*raw).output_string);
+            (*raw).output_string = output_string;
+        }
+    }
+}
+
+/// Stop capturing, and return what was captured so far,
+/// ending in `TRUNCATED_NOTE` if some output was dropped
+pub(crate) fn stop(stdout: &mut Output) -> Vec<u8> {
+    let raw = stdout as *mut Output as *mut RawOutput;
+    unsafe {
+        if CAPTURING.swap(false, Ordering::Relaxed) {
+            if let Some(original) = ORIGINAL {
+                (*raw).output_string = original;
+            }
+        }
+        let mut buffer = replace(addr_of_mut!(BUFFER), Vec::new());
+        if TRUNCATED.swap(false, Ordering::Relaxed) {
+            buffer.extend_from_slice(TRUNCATED_NOTE);
+        }
+        buffer
+    }
+}
+
+unsafe extern "efiapi" fn output_string(this: *mut RawOutput, string: *const u16) -> Status {
+    let mut len = 0;
+    while *string.add(len) != 0 {
+        len += 1;
+    }
+    let chars = core::slice::from_raw_parts(string, len);
+    let buffer = &mut *addr_of_mut!(BUFFER);
+    for c in char::decode_utf16(chars.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut encoded = [0; 4];
+        let bytes = c.encode_utf8(&mut encoded).as_bytes();
+        // leave room for the note, never growing the buffer
+        if buffer.len() + bytes.len() > CAPACITY - TRUNCATED_NOTE.len() {
+            TRUNCATED.store(true, Ordering::Relaxed);
+            break;
+        }
+        buffer.extend_from_slice(bytes);
+    }
+    match ORIGINAL {
+        Some(original) => original(this, string),
+        None => Status::SUCCESS,
+    }
+}
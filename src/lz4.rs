@@ -0,0 +1,282 @@
+//! Decompress LZ4 frames in memory
+//!
+//! Supports the frame format with independent or linked blocks, concatenated
+//! frames and skippable frames. Checksums are skipped without verifying them,
+//! and frames with a dictionary are rejected.
+
+use core::convert::TryInto;
+use core::fmt;
+
+/// The magic number starting an LZ4 frame
+pub const MAGIC: u32 = 0x184D_2204;
+/// The magic numbers of skippable frames, with any value in the low 4 bits
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+/// Error of a malformed frame
+#[derive(Debug)]
+pub enum Lz4Error {
+    /// The data ends within a frame
+    Truncated,
+    /// A frame has an unknown magic number or version
+    BadFrame { offset: usize },
+    /// A frame needs a dictionary, which is not supported
+    Dictionary { offset: usize },
+    /// A match refers to data before the start of the output
+    BadOffset { offset: usize },
+    /// The output does not fit into the buffer
+    Overflow,
+}
+
+impl fmt::Display for Lz4Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lz4Error::Truncated => write!(f, "data is truncated"),
+            Lz4Error::BadFrame { offset } => write!(f, "bad frame at {:#x}", offset),
+            Lz4Error::Dictionary { offset } => {
+                write!(f, "frame at {:#x} needs a dictionary", offset)
+            }
+            Lz4Error::BadOffset { offset } => write!(f, "bad match offset at {:#x}", offset),
+            Lz4Error::Overflow => write!(f, "output is larger than expected"),
+        }
+    }
+}
+
+/// Whether `data` starts with an LZ4 frame
+pub fn is_lz4(data: &[u8]) -> bool {
+    data.len() >= 4 && u32::from_le_bytes(data[..4].try_into().unwrap()) == MAGIC
+}
+
+/// The size of the decompressed contents of the frames in `data`
+pub fn decompressed_size(data: &[u8]) -> Result<usize, Lz4Error> {
+    decode(data, &mut Output::Count(0))
+}
+
+/// Decompress the frames in `data` into the start of `buf`, and return the size
+pub fn decompress(data: &[u8], buf: &mut [u8]) -> Result<usize, Lz4Error> {
+    decode(data, &mut Output::Buf(buf, 0))
+}
+
+/// Where decompressed data goes, with the number of bytes so far
+enum Output<'a> {
+    /// Only count the bytes
+    Count(usize),
+    Buf(&'a mut [u8], usize),
+}
+
+impl Output<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Output::Count(len) | Output::Buf(_, len) => *len,
+        }
+    }
+
+    fn push_slice(&mut self, bytes: &[u8]) -> Result<(), Lz4Error> {
+        match self {
+            Output::Count(len) => *len += bytes.len(),
+            Output::Buf(buf, len) => {
+                buf.get_mut(*len..*len + bytes.len())
+                    .ok_or(Lz4Error::Overflow)?
+                    .copy_from_slice(bytes);
+                *len += bytes.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `count` bytes starting `distance` bytes back, which may overlap
+    fn push_match(&mut self, distance: usize, count: usize) -> Result<(), Lz4Error> {
+        match self {
+            Output::Count(len) => *len += count,
+            Output::Buf(buf, len) => {
+                if buf.len() < *len + count {
+                    return Err(Lz4Error::Overflow);
+                }
+                for i in *len..*len + count {
+                    buf[i] = buf[i - distance];
+                }
+                *len += count;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A cursor over the input
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Lz4Error> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(Lz4Error::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Lz4Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Lz4Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Read the rest of a length whose first part was 15
+    fn length(&mut self, mut len: usize) -> Result<usize, Lz4Error> {
+        loop {
+            let byte = self.u8()?;
+            len += byte as usize;
+            if byte != 255 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+fn decode(data: &[u8], output: &mut Output) -> Result<usize, Lz4Error> {
+    let mut input = Input { data, pos: 0 };
+    while input.pos < data.len() {
+        let offset = input.pos;
+        let magic = input.u32()?;
+        if magic & 0xFFFF_FFF0 == SKIPPABLE_MAGIC {
+            let size = input.u32()? as usize;
+            input.bytes(size)?;
+            continue;
+        }
+        if magic != MAGIC {
+            return Err(Lz4Error::BadFrame { offset });
+        }
+        let flags = input.u8()?;
+        let _block_descriptor = input.u8()?;
+        if flags >> 6 != 0b01 {
+            return Err(Lz4Error::BadFrame { offset });
+        }
+        if flags & 0x01 != 0 {
+            return Err(Lz4Error::Dictionary { offset });
+        }
+        let block_checksum = flags & 0x10 != 0;
+        let content_size = flags & 0x08 != 0;
+        let content_checksum = flags & 0x04 != 0;
+        if content_size {
+            input.bytes(8)?;
+        }
+        // header checksum
+        input.u8()?;
+        loop {
+            let size = input.u32()?;
+            if size == 0 {
+                break;
+            }
+            let block = input.bytes((size & 0x7FFF_FFFF) as usize)?;
+            if size & 0x8000_0000 != 0 {
+                output.push_slice(block)?;
+            } else {
+                decode_block(block, output)?;
+            }
+            if block_checksum {
+                input.bytes(4)?;
+            }
+        }
+        if content_checksum {
+            input.bytes(4)?;
+        }
+    }
+    Ok(output.len())
+}
+
+/// Decode the sequences of a compressed block. Matches may refer to the
+/// output of previous blocks, as with linked blocks.
+fn decode_block(block: &[u8], output: &mut Output) -> Result<(), Lz4Error> {
+    let mut input = Input {
+        data: block,
+        pos: 0,
+    };
+    loop {
+        let token = input.u8()?;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = input.length(literals)?;
+        }
+        output.push_slice(input.bytes(literals)?)?;
+        // the last sequence has only literals
+        if input.pos == block.len() {
+            return Ok(());
+        }
+        let offset = input.pos;
+        let distance = u16::from_le_bytes(input.bytes(2)?.try_into().unwrap()) as usize;
+        if distance == 0 || distance > output.len() {
+            return Err(Lz4Error::BadOffset { offset });
+        }
+        let mut count = (token & 0x0F) as usize;
+        if count == 15 {
+            count = input.length(count)?;
+        }
+        output.push_match(distance, count + 4)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `b"abc" * 20 + b"end of the data\n"` compressed by `lz4 -9` 1.9.4: 3 literals,
+    /// a match overlapping its own output, 16 literals, then the end mark and the
+    /// content checksum
+    const FRAME: [u8; 44] = [
+        0x04, 0x22, 0x4d, 0x18, 0x64, 0x40, 0xa7, 0x19, 0x00, 0x00, 0x00, 0x3f, 0x61, 0x62, 0x63,
+        0x03, 0x00, 0x26, 0xf0, 0x01, 0x65, 0x6e, 0x64, 0x20, 0x6f, 0x66, 0x20, 0x74, 0x68, 0x65,
+        0x20, 0x64, 0x61, 0x74, 0x61, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x71, 0x69, 0x15,
+    ];
+
+    fn expected() -> Vec<u8> {
+        let mut data = b"abc".repeat(20);
+        data.extend_from_slice(b"end of the data\n");
+        data
+    }
+
+    #[test]
+    fn decompresses_frame() {
+        let expected = expected();
+        assert!(is_lz4(&FRAME));
+        assert_eq!(decompressed_size(&FRAME).unwrap(), expected.len());
+        let mut buf = vec![0; expected.len()];
+        assert_eq!(decompress(&FRAME, &mut buf).unwrap(), expected.len());
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn refuses_small_buffer() {
+        let mut buf = vec![0; expected().len() - 1];
+        assert!(matches!(
+            decompress(&FRAME, &mut buf),
+            Err(Lz4Error::Overflow)
+        ));
+    }
+
+    #[test]
+    fn refuses_truncated_frame() {
+        // within the content checksum, the end mark and the block
+        for len in [FRAME.len() - 1, FRAME.len() - 6, 20, 5] {
+            assert!(
+                matches!(decompressed_size(&FRAME[..len]), Err(Lz4Error::Truncated)),
+                "truncated to {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_bad_magic() {
+        let mut frame = FRAME;
+        frame[0] = 0x05;
+        assert!(!is_lz4(&frame));
+        assert!(matches!(
+            decompressed_size(&frame),
+            Err(Lz4Error::BadFrame { offset: 0 })
+        ));
+    }
+}
@@ -5,9 +5,11 @@
 //! 3. Map ELF segments to virtual memory
 //! 4. Map kernel stack and all physical memory
 //! 5. Exit boot and jump to ELF entry
+//!
+//! The steps after loading the config are in `boot::run`.
 
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(abi_efiapi)]
 
 #[macro_use]
@@ -15,237 +17,80 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
-use alloc::boxed::Box;
-use alloc::vec::Vec;
-use core::arch::asm;
-use rboot::{BootInfo, GraphicInfo};
-use uefi::proto::console::gop::GraphicsOutput;
-use uefi::proto::media::file::*;
-use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::table::boot::*;
-use uefi::table::cfg::{ACPI2_GUID, SMBIOS_GUID};
-use uefi::{prelude::*, CStr16};
-use x86_64::registers::control::*;
-use x86_64::structures::paging::*;
-use x86_64::{PhysAddr, VirtAddr};
-use xmas_elf::ElfFile;
+use uefi::prelude::*;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
 
+mod acpi;
+mod boot;
 mod config;
+mod crc32;
+mod elf;
+mod esp;
+mod fat;
+mod fbcon;
+mod fs;
+mod graphic;
+mod kaslr;
+mod la57;
+mod loadfile;
+mod logfile;
+mod lz4;
 mod page_table;
+mod smbios;
+mod tar;
+mod var;
 
 const CONFIG_PATH: &str = "\\EFI\\Boot\\rboot.conf";
 
+#[cfg_attr(test, allow(unused_mut))]
 #[entry]
 fn efi_main(image: uefi::Handle, mut st: SystemTable<Boot>) -> Status {
     // Initialize utilities (logging, memory allocation...)
+    // Not linked into unit tests, which run on the host with its allocator
+    #[cfg(not(test))]
     uefi_services::init(&mut st).expect("failed to initialize utilities");
 
     info!("bootloader is running");
+    check_long_mode();
     let bs = st.boot_services();
-    let config = {
-        let mut file = open_file(bs, CONFIG_PATH);
-        let buf = load_file(bs, &mut file);
-        config::Config::parse(buf)
-    };
-
-    let graphic_info = init_graphic(bs, config.resolution);
-    info!("config: {:#x?}", config);
-
-    let acpi2_addr = st
-        .config_table()
-        .iter()
-        .find(|entry| entry.guid == ACPI2_GUID)
-        .expect("failed to find ACPI 2 RSDP")
-        .address;
-    info!("acpi2: {:?}", acpi2_addr);
-
-    let smbios_addr = st
-        .config_table()
-        .iter()
-        .find(|entry| entry.guid == SMBIOS_GUID)
-        .expect("failed to find SMBIOS")
-        .address;
-    info!("smbios: {:?}", smbios_addr);
-
-    let elf = {
-        let mut file = open_file(bs, config.kernel_path);
-        let buf = load_file(bs, &mut file);
-        ElfFile::new(buf).expect("failed to parse ELF")
-    };
-    unsafe {
-        ENTRY = elf.header.pt2.entry_point() as usize;
-    }
-
-    let (initramfs_addr, initramfs_size) = if let Some(path) = config.initramfs {
-        let mut file = open_file(bs, path);
-        let buf = load_file(bs, &mut file);
-        (buf.as_ptr() as u64, buf.len() as u64)
-    } else {
-        (0, 0)
-    };
-
-    let max_mmap_size = st.boot_services().memory_map_size().map_size;
-    let mmap_storage = Box::leak(vec![0; max_mmap_size * 2].into_boxed_slice());
-    let mmap_iter = st
-        .boot_services()
-        .memory_map(mmap_storage)
-        .expect("failed to get memory map")
-        .1;
-    let max_phys_addr = mmap_iter
-        .map(|m| m.phys_start + m.page_count * 0x1000)
-        .max()
-        .unwrap()
-        .max(0x1_0000_0000); // include IOAPIC MMIO area
-
-    let mut page_table = current_page_table();
-    // root page table is readonly
-    // disable write protect
-    unsafe {
-        Cr0::update(|f| f.remove(Cr0Flags::WRITE_PROTECT));
-        Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
-    }
-    page_table::map_elf(&elf, &mut page_table, &mut UEFIFrameAllocator(bs))
-        .expect("failed to map ELF");
-    page_table::map_stack(
-        config.kernel_stack_address,
-        config.kernel_stack_size,
-        &mut page_table,
-        &mut UEFIFrameAllocator(bs),
-    )
-    .expect("failed to map stack");
-    page_table::map_physical_memory(
-        config.physical_memory_offset,
-        max_phys_addr,
-        &mut page_table,
-        &mut UEFIFrameAllocator(bs),
-    );
-    // recover write protect
-    unsafe {
-        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
-    }
-
-    info!("exit boot services");
-
-    let mut memory_map = Vec::with_capacity(128);
-
-    let (_rt, mmap_iter) = st
-        .exit_boot_services(image, mmap_storage)
-        .expect("Failed to exit boot services");
-    // NOTE: alloc & log can no longer be used
-
-    for desc in mmap_iter {
-        memory_map.push(desc);
-    }
-
-    // construct BootInfo
-    let bootinfo = BootInfo {
-        memory_map,
-        physical_memory_offset: config.physical_memory_offset,
-        graphic_info,
-        acpi2_rsdp_addr: acpi2_addr as u64,
-        smbios_addr: smbios_addr as u64,
-        initramfs_addr,
-        initramfs_size,
-        cmdline: config.cmdline,
-    };
-    let stacktop = config.kernel_stack_address + config.kernel_stack_size * 0x1000;
-    unsafe {
-        jump_to_entry(&bootinfo, stacktop);
-    }
-}
-
-/// Open file at `path`
-fn open_file(bs: &BootServices, path: &str) -> RegularFile {
-    info!("opening file: {}", path);
-    // FIXME: use LoadedImageProtocol to get the FileSystem of this image
-    let fs = bs
-        .locate_protocol::<SimpleFileSystem>()
-        .expect("failed to get FileSystem");
-    let fs = unsafe { &mut *fs.get() };
-    // FIXME: convert `str` to `CStr16` without a fixed buf.
-    let mut buf = [0u16; 256];
-    let path = CStr16::from_str_with_buf(path, &mut buf).expect("failed to convert path to ucs-2");
-    let mut root = fs.open_volume().expect("failed to open volume");
-    let handle = root
-        .open(path, FileMode::Read, FileAttribute::empty())
-        .expect("failed to open file");
-
-    match handle.into_type().expect("failed to into_type") {
-        FileType::Regular(regular) => regular,
-        _ => panic!("Invalid file type"),
-    }
-}
-
-/// Load file to new allocated pages
-fn load_file(bs: &BootServices, file: &mut RegularFile) -> &'static mut [u8] {
-    info!("loading file to memory");
-    let mut info_buf = [0u8; 0x100];
-    let info = file
-        .get_info::<FileInfo>(&mut info_buf)
-        .expect("failed to get file info");
-    let pages = info.file_size() as usize / 0x1000 + 1;
-    let mem_start = bs
-        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
-        .expect("failed to allocate pages");
-    let buf = unsafe { core::slice::from_raw_parts_mut(mem_start as *mut u8, pages * 0x1000) };
-    let len = file.read(buf).expect("failed to read file");
-    &mut buf[..len]
-}
-
-/// If `resolution` is some, then set graphic mode matching the resolution.
-/// Return information of the final graphic mode.
-fn init_graphic(bs: &BootServices, resolution: Option<(usize, usize)>) -> GraphicInfo {
-    let gop = bs
-        .locate_protocol::<GraphicsOutput>()
-        .expect("failed to get GraphicsOutput");
-    let gop = unsafe { &mut *gop.get() };
-
-    if let Some(resolution) = resolution {
-        let mode = gop
-            .modes()
-            .find(|mode| {
-                let info = mode.info();
-                info.resolution() == resolution
-            })
-            .expect("graphic mode not found");
-        info!("switching graphic mode");
-        gop.set_mode(&mode).expect("Failed to set graphics mode");
-    }
-    GraphicInfo {
-        mode: gop.current_mode_info(),
-        fb_addr: gop.frame_buffer().as_mut_ptr() as u64,
-        fb_size: gop.frame_buffer().size() as u64,
-    }
+    let buf: &'static [u8] = fs::load(bs, image, None, CONFIG_PATH, 0);
+    let config = config::Config::parse(buf).unwrap_or_else(|e| {
+        error!("invalid config {}: {}, using the defaults", CONFIG_PATH, e);
+        config::DEFAULT_CONFIG
+    });
+    boot::log_checksum(&config, CONFIG_PATH, buf);
+    boot::run(image, st, config)
 }
 
-/// Get current page table from CR3
-fn current_page_table() -> OffsetPageTable<'static> {
-    let p4_table_addr = Cr3::read().0.start_address().as_u64();
-    let p4_table = unsafe { &mut *(p4_table_addr as *mut PageTable) };
-    unsafe { OffsetPageTable::new(p4_table, VirtAddr::new(0)) }
-}
-
-/// Use `BootServices::allocate_pages()` as frame allocator
-struct UEFIFrameAllocator<'a>(&'a BootServices);
-
-unsafe impl FrameAllocator<Size4KiB> for UEFIFrameAllocator<'_> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let addr = self
-            .0
-            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
-            .expect("failed to allocate frame");
-        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
-        Some(frame)
+/// Check that the firmware left the CPU in long mode with paging enabled,
+/// as rboot and the page table setup rely on it.
+fn check_long_mode() {
+    let cr0 = Cr0::read_raw();
+    let cr4 = Cr4::read_raw();
+    let efer = Efer::read_raw();
+    let expected = [
+        (cr0, Cr0Flags::PAGING.bits()),
+        (cr4, Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits()),
+        (
+            efer,
+            (EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE).bits(),
+        ),
+    ];
+    if expected.iter().any(|&(value, bits)| value & bits != bits) {
+        panic!(
+            "not in long mode with paging: cr0 {:#x}, cr4 {:#x}, efer {:#x}",
+            cr0, cr4, efer
+        );
     }
 }
 
-/// Jump to ELF entry according to global variable `ENTRY`
-unsafe fn jump_to_entry(bootinfo: *const BootInfo, stacktop: u64) -> ! {
-    asm!("mov rsp, {}; call {}", in(reg) stacktop, in(reg) ENTRY, in("rdi") bootinfo);
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    graphic::restore_mode();
+    error!("{}", info);
     loop {
-        asm!("nop");
+        x86_64::instructions::hlt();
     }
 }
-
-/// The entry point of kernel, set by BSP.
-static mut ENTRY: usize = 0;
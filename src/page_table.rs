@@ -1,34 +1,534 @@
 //! This file is modified from 'page_table.rs' in 'rust-osdev/bootloader'
 
+use alloc::vec::Vec;
+use core::fmt;
+use rboot::{MemoryRange, PageTableRegion};
 use x86_64::structures::paging::{mapper::*, *};
-use x86_64::{align_up, PhysAddr, VirtAddr};
+use x86_64::{align_down, align_up, PhysAddr, VirtAddr};
 use xmas_elf::{program, ElfFile};
 
+/// Error of mapping the kernel ELF, stack or physical memory
+#[derive(Debug)]
+pub enum MapError {
+    /// Mapping a page failed
+    Map(MapToError<Size4KiB>),
+    /// Two LOAD segments, given by index and virtual range, share pages
+    Overlap {
+        first: (usize, u64, u64),
+        second: (usize, u64, u64),
+    },
+    /// A LOAD segment, given by index, start and size, is not within `SEGMENT_LIMIT`
+    /// or not canonical
+    OutOfRange { index: usize, start: u64, size: u64 },
+    /// The ELF has no LOAD segment with contents, e.g. it is a relocatable object
+    NoLoadSegments,
+    /// A LOAD segment, given by index, virtual address and file offset, has file
+    /// contents whose address and offset differ within a page, so that they
+    /// can not be mapped from the file
+    Misaligned {
+        index: usize,
+        addr: u64,
+        offset: u64,
+    },
+    /// The virtual range `[start, end)` covers the null page, which `protect_null` forbids
+    NullPage { start: u64, end: u64 },
+    /// A segment, given by index, has a type which can not be parsed
+    BadSegmentType { index: usize, error: &'static str },
+    /// Physical memory up to `end` does not fit in the canonical half `offset`
+    /// lies in, without `direct_map_clamp`
+    NotCanonical { offset: u64, end: u64 },
+}
+
+/// The end of the address space usable by LOAD segments. The last page is
+/// left out, as rounding up an address within it overflows.
+const SEGMENT_LIMIT: u64 = 0xFFFF_FFFF_FFFF_F000;
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(e: MapToError<Size4KiB>) -> Self {
+        MapError::Map(e)
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Map(e) => write!(f, "{:?}", e),
+            MapError::Overlap { first, second } => write!(
+                f,
+                "segment {} ({:#x}..{:#x}) overlaps segment {} ({:#x}..{:#x})",
+                first.0, first.1, first.2, second.0, second.1, second.2
+            ),
+            MapError::OutOfRange { index, start, size } => write!(
+                f,
+                "segment {} at {:#x} with size {:#x} is not canonical or reaches the last page",
+                index, start, size
+            ),
+            MapError::NoLoadSegments => write!(f, "ELF has no loadable segments"),
+            MapError::Misaligned {
+                index,
+                addr,
+                offset,
+            } => write!(
+                f,
+                "segment {} at {:#x} is not page aligned like its file offset {:#x}",
+                index, addr, offset
+            ),
+            MapError::NullPage { start, end } => write!(
+                f,
+                "{:#x}..{:#x} would map the null page, which protect_null forbids",
+                start, end
+            ),
+            MapError::BadSegmentType { index, error } => {
+                write!(f, "segment {} has a bad type: {}", index, error)
+            }
+            MapError::NotCanonical { offset, end } => write!(
+                f,
+                "physical memory up to {:#x} does not fit at physical_memory_offset {:#x}",
+                end, offset
+            ),
+        }
+    }
+}
+
+/// How to initialize the `.bss` part of segments
+#[derive(Debug, Clone, Copy)]
+pub enum BssInit {
+    /// Zero it, as required by ELF
+    Zero,
+    /// Fill it with a pattern of `width` bytes repeating, for debugging
+    Fill { pattern: u64, width: u64 },
+    /// Leave whatever the allocated frames contain, the kernel zeroes it
+    Skip,
+}
+
+/// Map the LOAD segments of `elf` at their addresses plus `slide`,
+/// initializing their `.bss` part as `bss`.
+/// `extra_flags` are added to the flags of every segment.
+/// With `protect_null`, no segment may cover the null page.
+///
+/// File contents of segments aligned to at least 2MiB are mapped with 2MiB pages
+/// where their physical frames are aligned as well, e.g. with
+/// `preserve_phys_alignment`. Everything else is mapped with 4KiB pages.
 pub fn map_elf(
     elf: &ElfFile,
-    page_table: &mut impl Mapper<Size4KiB>,
+    slide: u64,
+    bss: BssInit,
+    extra_flags: PageTableFlags,
+    protect_null: bool,
+    page_table: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<PageTableRegion, MapError> {
     info!("mapping ELF");
+    let has_load = elf
+        .program_iter()
+        .any(|segment| segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0);
+    if !has_load {
+        return Err(MapError::NoLoadSegments);
+    }
+    check_range(elf, slide)?;
+    check_overlap(elf)?;
+    check_alignment(elf)?;
+    if protect_null {
+        let segments = elf.program_iter().filter(|segment| {
+            segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0
+        });
+        for segment in segments {
+            // in range, as checked above
+            let start = segment.virtual_addr() + slide;
+            check_null_page(start, start + segment.mem_size())?;
+        }
+    }
+    let mut frame_allocator = CountingAllocator::new(frame_allocator);
+    let mut data_frames = 0;
+    let mut page_size = Size4KiB::SIZE;
     let kernel_start = PhysAddr::new(elf.input.as_ptr() as u64);
-    for segment in elf.program_iter() {
-        map_segment(&segment, kernel_start, page_table, frame_allocator)?;
+    let mut total_pages = 0;
+    let (mut image_start, mut image_end) = (u64::MAX, 0);
+    for (index, segment) in elf.program_iter().enumerate() {
+        match segment.get_type() {
+            Ok(program::Type::Load) => {}
+            Ok(_) => continue,
+            Err(error) => return Err(MapError::BadSegmentType { index, error }),
+        }
+        if segment.mem_size() != 0 {
+            let start = segment.virtual_addr() + slide;
+            let end = start + segment.mem_size();
+            let pages = (align_up(end, Size4KiB::SIZE) - align_down(start, Size4KiB::SIZE))
+                / Size4KiB::SIZE;
+            let flags = segment.flags();
+            info!(
+                "segment {}: {:#x}..{:#x} filesz {:#x} memsz {:#x} {}{}{} {} pages",
+                index,
+                start,
+                end,
+                segment.file_size(),
+                segment.mem_size(),
+                if flags.is_read() { 'R' } else { '-' },
+                if flags.is_write() { 'W' } else { '-' },
+                if flags.is_execute() { 'X' } else { '-' },
+                pages
+            );
+            total_pages += pages;
+            image_start = image_start.min(start);
+            image_end = image_end.max(end);
+        }
+        let (frames, huge) = map_segment(
+            &segment,
+            kernel_start,
+            slide,
+            bss,
+            extra_flags,
+            page_table,
+            &mut frame_allocator,
+        )?;
+        data_frames += frames;
+        if huge {
+            page_size = Size2MiB::SIZE;
+        }
+    }
+    if total_pages != 0 {
+        info!(
+            "mapped {} pages ({:#x} bytes) for kernel image {:#x}..{:#x}",
+            total_pages,
+            total_pages * Size4KiB::SIZE,
+            image_start,
+            image_end
+        );
+    }
+    Ok(PageTableRegion {
+        table_frames: frame_allocator.frames - data_frames,
+        page_size,
+    })
+}
+
+/// Check that the LOAD segments of `elf` at their addresses plus `slide`, and
+/// at their linked addresses, lie in the canonical address space below
+/// `SEGMENT_LIMIT`, so that computing their page ranges does not overflow
+fn check_range(elf: &ElfFile, slide: u64) -> Result<(), MapError> {
+    let segments = elf.program_iter().enumerate().filter(|(_, segment)| {
+        segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0
+    });
+    for (index, segment) in segments {
+        let size = segment.mem_size();
+        for start in [
+            Some(segment.virtual_addr()),
+            segment.virtual_addr().checked_add(slide),
+        ] {
+            let in_range = start.and_then(|start| {
+                let end = start.checked_add(size)?;
+                let canonical =
+                    VirtAddr::try_new(start).is_ok() && VirtAddr::try_new(end - 1).is_ok();
+                Some(end <= SEGMENT_LIMIT && canonical)
+            });
+            if in_range != Some(true) {
+                return Err(MapError::OutOfRange {
+                    index,
+                    start: segment.virtual_addr().wrapping_add(slide),
+                    size,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that no two LOAD segments of `elf` share a page,
+/// which would only fail while mapping the second one
+fn check_overlap(elf: &ElfFile) -> Result<(), MapError> {
+    let segments: Vec<_> = elf
+        .program_iter()
+        .enumerate()
+        .filter(|(_, segment)| {
+            segment.get_type() == Ok(program::Type::Load) && segment.mem_size() != 0
+        })
+        .map(|(index, segment)| {
+            let start = segment.virtual_addr();
+            (index, start, start + segment.mem_size())
+        })
+        .collect();
+    let pages = |(_, start, end): (usize, u64, u64)| {
+        (
+            align_down(start, Size4KiB::SIZE),
+            align_up(end, Size4KiB::SIZE),
+        )
+    };
+    for (i, &first) in segments.iter().enumerate() {
+        for &second in &segments[i + 1..] {
+            let (first_start, first_end) = pages(first);
+            let (second_start, second_end) = pages(second);
+            if first_start < second_end && second_start < first_end {
+                return Err(MapError::Overlap { first, second });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the file contents of each LOAD segment of `elf` start at the
+/// same offset within a page in memory and in the file, as their pages are
+/// mapped to the frames of the file
+fn check_alignment(elf: &ElfFile) -> Result<(), MapError> {
+    let segments = elf.program_iter().enumerate().filter(|(_, segment)| {
+        segment.get_type() == Ok(program::Type::Load) && segment.file_size() != 0
+    });
+    for (index, segment) in segments {
+        if segment.virtual_addr().wrapping_sub(segment.offset()) % Size4KiB::SIZE != 0 {
+            return Err(MapError::Misaligned {
+                index,
+                addr: segment.virtual_addr(),
+                offset: segment.offset(),
+            });
+        }
     }
     Ok(())
 }
 
+/// Map the physical range `range` at the same virtual addresses, writable and
+/// executable, with `extra_flags`.
+///
+/// Pages which are already identity mapped, e.g. by the firmware, are kept and
+/// made executable, which for a huge page applies to all of it.
+pub fn identity_map(
+    range: MemoryRange,
+    extra_flags: PageTableFlags,
+    page_table: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PageTableRegion, MapToError<Size4KiB>> {
+    info!("identity mapping {:#x}..{:#x}", range.start, range.end);
+    let mut frame_allocator = CountingAllocator::new(frame_allocator);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | extra_flags;
+    let mut addr = align_down(range.start, Size4KiB::SIZE);
+    while addr < range.end {
+        let virt = VirtAddr::new(addr);
+        match page_table.translate(virt) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags: mapped_flags,
+            } => {
+                if frame.start_address() + offset != PhysAddr::new(addr) {
+                    return Err(MapToError::PageAlreadyMapped(
+                        PhysFrame::containing_address(frame.start_address()),
+                    ));
+                }
+                if mapped_flags.contains(PageTableFlags::NO_EXECUTE) {
+                    let new_flags = mapped_flags - PageTableFlags::NO_EXECUTE;
+                    let result = unsafe {
+                        match frame {
+                            MappedFrame::Size4KiB(_) => page_table
+                                .update_flags(Page::<Size4KiB>::containing_address(virt), new_flags)
+                                .map(MapperFlush::flush),
+                            MappedFrame::Size2MiB(_) => page_table
+                                .update_flags(Page::<Size2MiB>::containing_address(virt), new_flags)
+                                .map(MapperFlush::flush),
+                            MappedFrame::Size1GiB(_) => page_table
+                                .update_flags(Page::<Size1GiB>::containing_address(virt), new_flags)
+                                .map(MapperFlush::flush),
+                        }
+                    };
+                    result.map_err(|_| MapToError::ParentEntryHugePage)?;
+                }
+                // skip the rest of the mapped page
+                addr += frame.size() - offset;
+            }
+            _ => {
+                let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+                unsafe {
+                    page_table
+                        .map_to(
+                            Page::containing_address(virt),
+                            frame,
+                            flags,
+                            &mut frame_allocator,
+                        )?
+                        .flush();
+                }
+                addr += Size4KiB::SIZE;
+            }
+        }
+    }
+    Ok(PageTableRegion {
+        table_frames: frame_allocator.frames,
+        page_size: Size4KiB::SIZE,
+    })
+}
+
+/// Clear the accessed and dirty bits of the pages mapping `[start, end)`, of any
+/// page size, for kernels which track their accesses from the start.
+/// Unmapped pages are skipped, page table entries above the pages are kept.
+pub fn clear_access_bits(
+    start: u64,
+    end: u64,
+    page_table: &mut (impl Translate + Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>),
+) {
+    update_leaf_flags(start, end, page_table, |flags| {
+        flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY)
+    })
+    .expect("failed to clear access bits");
+}
+
+/// Make the pages mapping `[start, end)` non-executable, of any page size,
+/// which for a huge page applies to all of it. Unmapped pages are skipped.
+pub fn set_no_execute(
+    start: u64,
+    end: u64,
+    page_table: &mut (impl Translate + Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>),
+) {
+    update_leaf_flags(start, end, page_table, |flags| {
+        flags | PageTableFlags::NO_EXECUTE
+    })
+    .expect("failed to set no-execute");
+}
+
+/// Make the pages mapping `[start, end)` write-combining, through the PAT entry
+/// selected by `PageTableFlags::WRITE_THROUGH` alone, which must be programmed
+/// as write-combining before they are used. Unmapped pages are skipped.
+pub fn set_write_combining(
+    start: u64,
+    end: u64,
+    page_table: &mut (impl Translate + Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>),
+) {
+    update_leaf_flags(start, end, page_table, |flags| {
+        (flags - PageTableFlags::NO_CACHE) | PageTableFlags::WRITE_THROUGH
+    })
+    .expect("failed to set write-combining");
+}
+
+/// Remap the 2MiB pages covering parts of `[start, end)` with 4KiB pages of the
+/// same flags, so that flags can be set on exactly the pages mapping it.
+/// Unmapped and 4KiB pages are skipped.
+/// Return the number of frames allocated for page tables.
+pub fn split_huge_pages(
+    start: u64,
+    end: u64,
+    page_table: &mut (impl Translate + Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let mut frame_allocator = CountingAllocator::new(frame_allocator);
+    let mut addr = align_down(start, Size2MiB::SIZE);
+    while addr < end {
+        let virt = match VirtAddr::try_new(addr) {
+            Ok(virt) => virt,
+            Err(_) => break,
+        };
+        if let TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(frame),
+            flags,
+            ..
+        } = page_table.translate(virt)
+        {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            match page_table.unmap(page) {
+                Ok((_, flush)) => flush.ignore(),
+                Err(UnmapError::ParentEntryHugePage) => {
+                    return Err(MapToError::ParentEntryHugePage)
+                }
+                Err(_) => unreachable!(),
+            }
+            let flags = flags - PageTableFlags::HUGE_PAGE;
+            for offset in (0..Size2MiB::SIZE).step_by(Size4KiB::SIZE as usize) {
+                let page = Page::<Size4KiB>::containing_address(virt + offset);
+                let frame = PhysFrame::containing_address(frame.start_address() + offset);
+                unsafe {
+                    page_table
+                        .map_to(page, frame, flags, &mut frame_allocator)?
+                        .ignore();
+                }
+            }
+        }
+        addr = match addr.checked_add(Size2MiB::SIZE) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(frame_allocator.frames)
+}
+
+/// Replace the flags of the pages mapping `[start, end)` with `update` of them,
+/// up to the first address which is not canonical.
+/// The TLB is not flushed, which is done before jumping to the kernel.
+fn update_leaf_flags(
+    start: u64,
+    end: u64,
+    page_table: &mut (impl Translate + Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>),
+    update: impl Fn(PageTableFlags) -> PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let mut addr = align_down(start, Size4KiB::SIZE);
+    while addr < end {
+        let virt = match VirtAddr::try_new(addr) {
+            Ok(virt) => virt,
+            Err(_) => break,
+        };
+        let size = match page_table.translate(virt) {
+            TranslateResult::Mapped { frame, flags, .. } => {
+                let flags = update(flags);
+                unsafe {
+                    match frame {
+                        MappedFrame::Size4KiB(_) => page_table
+                            .update_flags(Page::<Size4KiB>::containing_address(virt), flags)?
+                            .ignore(),
+                        MappedFrame::Size2MiB(_) => page_table
+                            .update_flags(Page::<Size2MiB>::containing_address(virt), flags)?
+                            .ignore(),
+                        MappedFrame::Size1GiB(_) => page_table
+                            .update_flags(Page::<Size1GiB>::containing_address(virt), flags)?
+                            .ignore(),
+                    }
+                }
+                frame.size()
+            }
+            _ => Size4KiB::SIZE,
+        };
+        addr = match align_down(addr, size).checked_add(size) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// The physical address `virt` is mapped to in `page_table`, of any page size,
+/// or none if it is not mapped
+pub fn translate_addr(page_table: &impl Translate, virt: VirtAddr) -> Option<PhysAddr> {
+    page_table.translate_addr(virt)
+}
+
+/// The physical address `virt` is mapped to in `page_table` with the flags of
+/// the page mapping it, or none if it is not mapped
+pub fn translate_flags(
+    page_table: &impl Translate,
+    virt: VirtAddr,
+) -> Option<(PhysAddr, PageTableFlags)> {
+    match page_table.translate(virt) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    }
+}
+
 pub fn map_stack(
     addr: u64,
     pages: u64,
+    mapped_pages: u64,
+    extra_flags: PageTableFlags,
+    protect_null: bool,
     page_table: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<PageTableRegion, MapError> {
     info!("mapping stack at {:#x}", addr);
-    // create a stack
-    let stack_start = Page::containing_address(VirtAddr::new(addr));
-    let stack_end = stack_start + pages;
+    if protect_null {
+        check_null_page(addr, addr.saturating_add(pages * Size4KiB::SIZE))?;
+    }
+    let mut frame_allocator = CountingAllocator::new(frame_allocator);
+    // create a stack, of which only the top `mapped_pages` are mapped
+    let stack_end = Page::containing_address(VirtAddr::new(addr)) + pages;
+    let pages = mapped_pages.min(pages);
+    let stack_start = stack_end - pages;
 
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | extra_flags;
 
     for page in Page::range(stack_start, stack_end) {
         let frame = frame_allocator
@@ -36,36 +536,43 @@ pub fn map_stack(
             .ok_or(MapToError::FrameAllocationFailed)?;
         unsafe {
             page_table
-                .map_to(page, frame, flags, frame_allocator)?
+                .map_to(page, frame, flags, &mut frame_allocator)?
                 .flush();
         }
     }
 
-    Ok(())
+    Ok(PageTableRegion {
+        table_frames: frame_allocator.frames - pages,
+        page_size: Size4KiB::SIZE,
+    })
 }
 
 fn map_segment(
     segment: &program::ProgramHeader,
     kernel_start: PhysAddr,
-    page_table: &mut impl Mapper<Size4KiB>,
+    slide: u64,
+    bss: BssInit,
+    extra_flags: PageTableFlags,
+    page_table: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    if segment.get_type().unwrap() != program::Type::Load {
-        return Ok(());
+) -> Result<(u64, bool), MapError> {
+    if segment.get_type() != Ok(program::Type::Load) {
+        return Ok((0, false));
     }
-    debug!("mapping segment: {:#x?}", segment);
+    trace!("mapping segment: {:#x?}", segment);
     let mem_size = segment.mem_size();
     let file_size = segment.file_size();
-    let file_offset = segment.offset() & !0xfff;
-    let phys_start_addr = kernel_start + file_offset;
-    let virt_start_addr = VirtAddr::new(segment.virtual_addr());
+    // the address and offset start at the same offset within a page, as
+    // checked by `check_alignment`, so pages and frames correspond
+    let phys_start_addr = kernel_start + segment.offset();
+    let virt_start_addr = VirtAddr::new(segment.virtual_addr() + slide);
 
     let start_page: Page = Page::containing_address(virt_start_addr);
     let start_frame = PhysFrame::containing_address(phys_start_addr);
     let end_frame = PhysFrame::containing_address(phys_start_addr + file_size - 1u64);
 
     let flags = segment.flags();
-    let mut page_table_flags = PageTableFlags::PRESENT;
+    let mut page_table_flags = PageTableFlags::PRESENT | extra_flags;
     if !flags.is_execute() {
         page_table_flags |= PageTableFlags::NO_EXECUTE
     };
@@ -73,21 +580,56 @@ fn map_segment(
         page_table_flags |= PageTableFlags::WRITABLE
     };
 
-    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-        let offset = frame - start_frame;
+    let huge = segment.align() >= Size2MiB::SIZE;
+    let mut huge_mapped = false;
+    let mut data_frames = 0;
+    let file_end = virt_start_addr + file_size;
+    let frames = if file_size == 0 {
+        0
+    } else {
+        end_frame - start_frame + 1
+    };
+    let mut offset = 0;
+    while offset < frames {
         let page = start_page + offset;
-        unsafe {
-            page_table
-                .map_to(page, frame, page_table_flags, frame_allocator)?
-                .flush();
+        let frame = start_frame + offset;
+        // a 2MiB page must only hold file contents of this segment
+        if huge
+            && page.start_address().is_aligned(Size2MiB::SIZE)
+            && frame.start_address().is_aligned(Size2MiB::SIZE)
+            && page.start_address() + Size2MiB::SIZE <= file_end
+        {
+            let page = Page::<Size2MiB>::containing_address(page.start_address());
+            let frame = PhysFrame::<Size2MiB>::containing_address(frame.start_address());
+            unsafe {
+                page_table
+                    .map_to(page, frame, page_table_flags, frame_allocator)
+                    .map_err(huge_page_error)?
+                    .flush();
+            }
+            huge_mapped = true;
+            offset += Size2MiB::SIZE / Size4KiB::SIZE;
+        } else {
+            unsafe {
+                page_table
+                    .map_to(page, frame, page_table_flags, frame_allocator)?
+                    .flush();
+            }
+            offset += 1;
         }
     }
 
     if mem_size > file_size {
-        // .bss section (or similar), which needs to be zeroed
+        // .bss section (or similar), which needs to be zeroed.
+        // Its frames are mapped even if `BssInit::Skip` leaves them as they are.
+        //
+        // It is zeroed through the physical addresses of its frames, which the
+        // firmware identity maps, rather than through the kernel virtual
+        // addresses, so this does not depend on the mapping just created
+        // being active in the current page table.
         let zero_start = virt_start_addr + file_size;
         let zero_end = virt_start_addr + mem_size;
-        if zero_start.as_u64() & 0xfff != 0 {
+        if file_size != 0 && zero_start.as_u64() & 0xfff != 0 {
             // A part of the last mapped frame needs to be zeroed. This is
             // not possible since it could already contains parts of the next
             // segment. Thus, we need to copy it before zeroing.
@@ -95,6 +637,7 @@ fn map_segment(
             let new_frame = frame_allocator
                 .allocate_frame()
                 .ok_or(MapToError::FrameAllocationFailed)?;
+            data_frames += 1;
 
             type PageArray = [u64; Size4KiB::SIZE as usize / 8];
 
@@ -109,65 +652,422 @@ fn map_segment(
 
             // remap last page
             if let Err(e) = page_table.unmap(last_page) {
-                return Err(match e {
+                return Err(MapError::Map(match e {
                     UnmapError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
                     UnmapError::PageNotMapped => unreachable!(),
                     UnmapError::InvalidFrameAddress(_) => unreachable!(),
-                });
+                }));
             }
             unsafe {
                 page_table
                     .map_to(last_page, new_frame, page_table_flags, frame_allocator)?
                     .flush();
             }
+
+            // zero the rest of the last page
+            let page_offset = zero_start.as_u64() & 0xfff;
+            let len = (Size4KiB::SIZE - page_offset).min(mem_size - file_size);
+            unsafe {
+                fill_bss(
+                    new_frame.start_address() + page_offset,
+                    zero_start,
+                    len,
+                    bss,
+                );
+            }
         }
 
-        // Map additional frames.
-        let start_page: Page =
-            Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)));
-        let end_page = Page::containing_address(zero_end);
+        // Map additional frames. Without file contents, the segment
+        // may start in the middle of its first page.
+        let start_page: Page = if file_size == 0 {
+            Page::containing_address(zero_start)
+        } else {
+            Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)))
+        };
+        let end_page = Page::containing_address(zero_end - 1u64);
         for page in Page::range_inclusive(start_page, end_page) {
             let frame = frame_allocator
                 .allocate_frame()
                 .ok_or(MapToError::FrameAllocationFailed)?;
+            data_frames += 1;
             unsafe {
                 page_table
                     .map_to(page, frame, page_table_flags, frame_allocator)?
                     .flush();
+                // zero the whole frame, it is not shared with anything else
+                fill_bss(
+                    frame.start_address(),
+                    page.start_address(),
+                    Size4KiB::SIZE,
+                    bss,
+                );
             }
         }
+    }
+    Ok((data_frames, huge_mapped))
+}
 
-        // zero bss
-        unsafe {
-            core::ptr::write_bytes(
-                zero_start.as_mut_ptr::<u8>(),
-                0,
-                (mem_size - file_size) as usize,
-            );
+/// Counts the frames allocated through it
+struct CountingAllocator<'a, A> {
+    inner: &'a mut A,
+    frames: u64,
+}
+
+impl<'a, A> CountingAllocator<'a, A> {
+    fn new(inner: &'a mut A) -> Self {
+        CountingAllocator { inner, frames: 0 }
+    }
+}
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for CountingAllocator<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.inner.allocate_frame()?;
+        self.frames += 1;
+        Some(frame)
+    }
+}
+
+/// Convert an error of mapping a 2MiB page to the error of `map_elf`
+fn huge_page_error(e: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match e {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
         }
     }
-    Ok(())
 }
 
-/// Map physical memory [0, max_addr)
-/// to virtual space [offset, offset + max_addr)
+/// Initialize `len` bytes at the physical address `start` as `bss`.
+/// `virt` is where `start` is mapped for the kernel.
+///
+/// A fill pattern repeats every `width` bytes, aligned to its width in the
+/// kernel's address space, so that aligned reads see the pattern itself.
+unsafe fn fill_bss(start: PhysAddr, virt: VirtAddr, len: u64, bss: BssInit) {
+    let ptr = start.as_u64() as *mut u8;
+    let (pattern, width) = match bss {
+        BssInit::Zero | BssInit::Fill { pattern: 0, .. } => {
+            core::ptr::write_bytes(ptr, 0, len as usize);
+            return;
+        }
+        BssInit::Fill { pattern, width } => (pattern, width),
+        BssInit::Skip => return,
+    };
+    let bytes = pattern.to_le_bytes();
+    for i in 0..len {
+        let index = (virt.as_u64() + i) % width;
+        ptr.add(i as usize).write(bytes[index as usize]);
+    }
+}
+
+/// Copy the page table rooted at `p4_frame` into newly allocated frames.
+///
+/// Huge page entries are copied as they are, so the copy maps the same memory,
+/// but can be modified while the firmware page table stays read-only.
+pub fn clone_page_table(
+    p4_frame: PhysFrame,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    info!("cloning page table");
+    clone_table(p4_frame, 4, frame_allocator)
+}
+
+fn clone_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let table = unsafe { &*(frame.start_address().as_u64() as *const PageTable) };
+    let new_frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let new_table = unsafe { &mut *(new_frame.start_address().as_u64() as *mut PageTable) };
+    new_table.zero();
+    for (entry, new_entry) in table.iter().zip(new_table.iter_mut()) {
+        let flags = entry.flags();
+        if level > 1
+            && flags.contains(PageTableFlags::PRESENT)
+            && !flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            let child = PhysFrame::containing_address(entry.addr());
+            new_entry.set_frame(clone_table(child, level - 1, frame_allocator)?, flags);
+        } else {
+            *new_entry = entry.clone();
+        }
+    }
+    Ok(new_frame)
+}
+
+/// Map the physical memory `ranges`, with their starts rounded down to 2MiB
+/// and their ends up to 4KiB, merged, to virtual space at `offset` plus their
+/// addresses. Ranges are mapped with 2MiB pages but for the part of their last
+/// 2MiB they cover, which is mapped with 4KiB pages rather than past their end,
+/// non-executable unless `executable` is set.
+/// With `clamp`, memory beyond the end of the canonical half `offset` lies in
+/// is left unmapped, instead of panicking.
+/// Return the ranges which are mapped, and the page tables used for them.
 pub fn map_physical_memory(
     offset: u64,
-    max_addr: u64,
-    page_table: &mut impl Mapper<Size2MiB>,
+    ranges: &[MemoryRange],
+    executable: bool,
+    clamp: bool,
+    protect_null: bool,
+    page_table: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+) -> Result<(Vec<MemoryRange>, PageTableRegion), MapError> {
     info!("mapping physical memory");
-    let start_frame = PhysFrame::containing_address(PhysAddr::new(0));
-    let end_frame = PhysFrame::containing_address(PhysAddr::new(max_addr));
-    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64() + offset));
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            page_table
-                .map_to(page, frame, flags, frame_allocator)
-                .expect("failed to map physical memory")
-                .flush();
+    let mut frame_allocator = CountingAllocator::new(frame_allocator);
+    let mut ranges: Vec<MemoryRange> = ranges
+        .iter()
+        .filter(|range| range.start < range.end)
+        .map(|range| MemoryRange {
+            start: align_down(range.start, Size2MiB::SIZE),
+            end: align_up(range.end, Size4KiB::SIZE),
+        })
+        .collect();
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<MemoryRange> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
         }
     }
+    if clamp {
+        clamp_ranges(offset, &mut merged);
+    }
+    // `VirtAddr::new` panics in the loop below for non-canonical addresses
+    check_fit(offset, &merged)?;
+    if protect_null {
+        for range in &merged {
+            // whether virtual address 0 lies within the range as mapped at `offset`
+            let start = offset.wrapping_add(range.start);
+            if start.wrapping_neg() < range.end - range.start {
+                return Err(MapError::NullPage {
+                    start,
+                    end: offset.wrapping_add(range.end),
+                });
+            }
+        }
+    }
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if !executable {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    for range in &merged {
+        let huge_end = align_down(range.end, Size2MiB::SIZE);
+        let start_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(range.start));
+        let end_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(huge_end));
+        for frame in PhysFrame::range(start_frame, end_frame) {
+            let page = Page::<Size2MiB>::containing_address(VirtAddr::new(
+                frame.start_address().as_u64() + offset,
+            ));
+            unsafe {
+                page_table
+                    .map_to(page, frame, flags, &mut frame_allocator)
+                    .map_err(huge_page_error)?
+                    .flush();
+            }
+        }
+        // the rest of the last 2MiB, not mapping beyond the end of the range
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(huge_end));
+        let end_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(range.end));
+        for frame in PhysFrame::range(start_frame, end_frame) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                frame.start_address().as_u64() + offset,
+            ));
+            unsafe {
+                page_table
+                    .map_to(page, frame, flags, &mut frame_allocator)?
+                    .flush();
+            }
+        }
+    }
+    let region = PageTableRegion {
+        table_frames: frame_allocator.frames,
+        page_size: Size2MiB::SIZE,
+    };
+    Ok((merged, region))
+}
+
+/// The size of the physical memory which fits at `offset`: up to the end of
+/// the canonical half `offset` lies in, or none if it is not canonical
+fn direct_map_limit(offset: u64) -> u64 {
+    // `VirtAddr::try_new` sign extends an offset with only bit 47 set
+    if VirtAddr::new_truncate(offset).as_u64() != offset {
+        0
+    } else if offset < 0x8000_0000_0000 {
+        0x8000_0000_0000 - offset
+    } else {
+        offset.wrapping_neg()
+    }
+}
+
+/// Leave out the parts of the sorted `ranges` which do not fit at `offset`
+fn clamp_ranges(offset: u64, ranges: &mut Vec<MemoryRange>) {
+    let limit = direct_map_limit(offset);
+    for range in ranges.iter_mut().filter(|range| range.end > limit) {
+        let start = range.start.max(limit);
+        warn!(
+            "physical memory {:#x}..{:#x} does not fit at offset {:#x}, leaving it unmapped",
+            start, range.end, offset
+        );
+        range.end = start;
+    }
+    ranges.retain(|range| range.start < range.end);
+}
+
+/// Refuse `ranges` if they do not all fit at `offset`
+fn check_fit(offset: u64, ranges: &[MemoryRange]) -> Result<(), MapError> {
+    let limit = direct_map_limit(offset);
+    match ranges.iter().find(|range| range.end > limit) {
+        Some(range) => Err(MapError::NotCanonical {
+            offset,
+            end: range.end,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Refuse to map `[start, end)` if it covers the null page
+fn check_null_page(start: u64, end: u64) -> Result<(), MapError> {
+    if start < Size4KiB::SIZE && start < end {
+        return Err(MapError::NullPage { start, end });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(spans: &[(u64, u64)]) -> Vec<MemoryRange> {
+        spans
+            .iter()
+            .map(|&(start, end)| MemoryRange { start, end })
+            .collect()
+    }
+
+    fn spans(ranges: &[MemoryRange]) -> Vec<(u64, u64)> {
+        ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    #[test]
+    fn clamps_at_top_of_address_space() {
+        let offset = 0xFFFF_FFFF_FFFF_F000;
+        assert_eq!(direct_map_limit(offset), 0x1000);
+        let mut mapped = ranges(&[(0, 0x20_0000), (0x40_0000, 0x60_0000)]);
+        assert!(matches!(
+            check_fit(offset, &mapped),
+            Err(MapError::NotCanonical { end: 0x20_0000, .. })
+        ));
+        clamp_ranges(offset, &mut mapped);
+        assert_eq!(spans(&mapped), [(0, 0x1000)]);
+        assert!(check_fit(offset, &mapped).is_ok());
+    }
+
+    #[test]
+    fn clamps_at_top_of_lower_half() {
+        let offset = 0x7FFF_FFFF_F000;
+        assert_eq!(direct_map_limit(offset), 0x1000);
+        let mut mapped = ranges(&[(0, 0x1000), (0x1000, 0x2000)]);
+        assert!(check_fit(offset, &mapped).is_err());
+        clamp_ranges(offset, &mut mapped);
+        assert_eq!(spans(&mapped), [(0, 0x1000)]);
+    }
+
+    #[test]
+    fn clamps_all_for_non_canonical_offset() {
+        let offset = 0x8000_0000_0000;
+        assert_eq!(direct_map_limit(offset), 0);
+        let mut mapped = ranges(&[(0, 0x1000)]);
+        assert!(check_fit(offset, &mapped).is_err());
+        clamp_ranges(offset, &mut mapped);
+        assert!(mapped.is_empty());
+    }
+
+    #[test]
+    fn keeps_ranges_which_fit() {
+        let offset = 0xFFFF_8000_0000_0000;
+        assert_eq!(direct_map_limit(offset), 0x8000_0000_0000);
+        let mut mapped = ranges(&[(0, 0x20_0000), (0x1_0000_0000, 0x2_0000_0000)]);
+        assert!(check_fit(offset, &mapped).is_ok());
+        clamp_ranges(offset, &mut mapped);
+        assert_eq!(
+            spans(&mapped),
+            [(0, 0x20_0000), (0x1_0000_0000, 0x2_0000_0000)]
+        );
+    }
+
+    /// `fill_bss` the middle of a buffer of 0x55, mapped at `virt`
+    fn filled(virt: u64, bss: BssInit) -> Vec<u8> {
+        let mut buf = vec![0x55u8; 16];
+        let start = PhysAddr::new(buf.as_mut_ptr() as u64 + 2);
+        unsafe { fill_bss(start, VirtAddr::new(virt), 12, bss) };
+        buf
+    }
+
+    #[test]
+    fn zeroes_bss() {
+        let mut expected = [0u8; 16];
+        expected[..2].fill(0x55);
+        expected[14..].fill(0x55);
+        assert_eq!(filled(0x1003, BssInit::Zero), expected);
+    }
+
+    #[test]
+    fn skips_bss() {
+        assert_eq!(filled(0x1003, BssInit::Skip), [0x55; 16]);
+    }
+
+    #[test]
+    fn fills_bss_aligned_to_pattern() {
+        // 0x1003 is the last byte of a pattern in the kernel's address space
+        assert_eq!(
+            filled(
+                0x1003,
+                BssInit::Fill {
+                    pattern: 0xDEAD_BEEF,
+                    width: 4
+                }
+            ),
+            [
+                0x55, 0x55, 0xDE, 0xEF, 0xBE, 0xAD, 0xDE, 0xEF, 0xBE, 0xAD, 0xDE, 0xEF, 0xBE, 0xAD,
+                0x55, 0x55
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_segment_alignment() {
+        use crate::elf::tests::{build_elf, bytes, Phdr, PT_LOAD};
+        let segment = |vaddr, offset| Phdr {
+            ty: PT_LOAD,
+            offset,
+            vaddr,
+            filesz: 0x100,
+            memsz: 0x100,
+            align: 0x1000,
+            ..Phdr::default()
+        };
+        let file = build_elf(0x20_1000, &[segment(0x20_1000, 0x1000)], 0x1100);
+        assert!(check_alignment(&ElfFile::new(bytes(&file)).unwrap()).is_ok());
+        // p_vaddr % p_align != p_offset % p_align
+        let file = build_elf(
+            0x20_1000,
+            &[segment(0x20_1000, 0x1000), segment(0x20_2100, 0x1000)],
+            0x1100,
+        );
+        assert!(matches!(
+            check_alignment(&ElfFile::new(bytes(&file)).unwrap()),
+            Err(MapError::Misaligned {
+                index: 1,
+                addr: 0x20_2100,
+                offset: 0x1000
+            })
+        ));
+    }
 }
@@ -0,0 +1,74 @@
+//! Minimal reading of the SMBIOS entry point, through the identity mapping before exit
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::convert::TryInto;
+
+/// Strings of the system information structure (type 1)
+#[derive(Debug, Default)]
+pub struct SystemInfo {
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+}
+
+/// Copy the 32-bit (`_SM_`) or 64-bit (`_SM3_`) entry point structure at `addr`
+pub fn entry_point(addr: u64) -> Option<&'static [u8]> {
+    let anchor = unsafe { core::ptr::read_unaligned(addr as *const [u8; 5]) };
+    let len_offset = if anchor == *b"_SM3_" {
+        6
+    } else if anchor[..4] == *b"_SM_" {
+        5
+    } else {
+        return None;
+    };
+    let len = unsafe { *((addr + len_offset) as *const u8) } as usize;
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    Some(Box::leak(bytes.into()))
+}
+
+/// Read the system information structure from the structure table of the
+/// entry point `entry`, as copied by `entry_point`
+pub fn system_info(entry: &[u8]) -> Option<SystemInfo> {
+    let (table_addr, table_len) = if entry.starts_with(b"_SM3_") {
+        let len = u32::from_le_bytes(entry.get(0x0C..0x10)?.try_into().unwrap());
+        let addr = u64::from_le_bytes(entry.get(0x10..0x18)?.try_into().unwrap());
+        (addr, len as usize)
+    } else {
+        let len = u16::from_le_bytes(entry.get(0x16..0x18)?.try_into().unwrap());
+        let addr = u32::from_le_bytes(entry.get(0x18..0x1C)?.try_into().unwrap());
+        (addr as u64, len as usize)
+    };
+    let table = unsafe { core::slice::from_raw_parts(table_addr as *const u8, table_len) };
+    let mut offset = 0;
+    // each structure is a formatted area, followed by strings ending with two NULs
+    while offset + 4 <= table.len() {
+        let ty = table[offset];
+        let len = table[offset + 1] as usize;
+        let formatted = table.get(offset..offset + len)?;
+        let strings_start = offset + len;
+        let strings_len = table
+            .get(strings_start..)?
+            .windows(2)
+            .position(|w| w == [0, 0])?;
+        let strings = &table[strings_start..strings_start + strings_len];
+        match ty {
+            1 if len >= 8 => {
+                // strings are referenced by 1-based index, 0 for none
+                let string = |index: u8| {
+                    let index = (index as usize).checked_sub(1)?;
+                    let bytes = strings.split(|&b| b == 0).nth(index)?;
+                    Some(String::from_utf8_lossy(bytes).trim().into())
+                };
+                return Some(SystemInfo {
+                    manufacturer: string(formatted[4]).unwrap_or_default(),
+                    product: string(formatted[5]).unwrap_or_default(),
+                    serial: string(formatted[7]).unwrap_or_default(),
+                });
+            }
+            127 => return None,
+            _ => offset = strings_start + strings_len + 2,
+        }
+    }
+    None
+}
@@ -0,0 +1,127 @@
+//! Read files from a tar archive in memory
+//!
+//! Supports ustar and GNU headers. Only regular files are used,
+//! other members (directories, links, extended headers) are skipped.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{fmt, str};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Error of a malformed archive
+#[derive(Debug)]
+pub enum TarError {
+    /// The archive ends within a header or member
+    Truncated,
+    /// A header has no ustar magic or a wrong checksum
+    BadHeader { offset: usize },
+    /// A header field is not a valid octal number or name
+    BadField { offset: usize },
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TarError::Truncated => write!(f, "archive is truncated"),
+            TarError::BadHeader { offset } => write!(f, "bad header at {:#x}", offset),
+            TarError::BadField { offset } => write!(f, "bad header field at {:#x}", offset),
+        }
+    }
+}
+
+/// A regular file in an archive
+pub struct Member<'a> {
+    /// The path of the member, without leading `./`
+    pub name: String,
+    pub data: &'a [u8],
+}
+
+/// A tar archive, whose members borrow from its bytes
+pub struct Archive<'a> {
+    members: Vec<Member<'a>>,
+}
+
+impl<'a> Archive<'a> {
+    /// Parse all headers of the archive in `data`
+    pub fn new(data: &'a [u8]) -> Result<Self, TarError> {
+        let mut members = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = data
+                .get(offset..offset + BLOCK_SIZE)
+                .ok_or(TarError::Truncated)?;
+            // the archive ends with zero blocks
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" || octal(&header[148..156]) != Some(checksum(header)) {
+                return Err(TarError::BadHeader { offset });
+            }
+            let size = octal(&header[124..136]).ok_or(TarError::BadField { offset })? as usize;
+            let start = offset + BLOCK_SIZE;
+            let member_data = data.get(start..start + size).ok_or(TarError::Truncated)?;
+            // regular file
+            if header[156] == b'0' || header[156] == 0 {
+                let name = field(&header[0..100]).ok_or(TarError::BadField { offset })?;
+                let prefix = field(&header[345..500]).ok_or(TarError::BadField { offset })?;
+                let mut path = String::from(prefix);
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(name);
+                members.push(Member {
+                    name: String::from(path.trim_start_matches("./")),
+                    data: member_data,
+                });
+            }
+            offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        }
+        Ok(Archive { members })
+    }
+
+    /// Find the member at `path`, or else the only one named like the last
+    /// component of `path`. Both `/` and `\` separate components.
+    pub fn find(&self, path: &str) -> Option<&Member<'a>> {
+        let path = path.replace('\\', "/");
+        let path = path.trim_start_matches('/');
+        if let Some(member) = self.members.iter().find(|m| m.name == path) {
+            return Some(member);
+        }
+        let mut found = self
+            .members
+            .iter()
+            .filter(|m| file_name(&m.name) == file_name(path));
+        match (found.next(), found.next()) {
+            (Some(member), None) => Some(member),
+            _ => None,
+        }
+    }
+}
+
+/// Sum of header bytes, with the checksum field taken as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum()
+}
+
+/// The last component of `path`
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Parse a NUL or space terminated octal number
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Parse a NUL terminated string
+fn field(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).ok()
+}
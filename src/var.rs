@@ -0,0 +1,38 @@
+//! UEFI variables owned by rboot
+
+use uefi::table::runtime::{RuntimeServices, VariableAttributes, VariableVendor};
+use uefi::{CStr16, Guid};
+
+/// Vendor GUID of rboot's variables
+pub const RBOOT_VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x6e1c_7a2b,
+    0x3f4d,
+    0x4b8e,
+    0x9a61,
+    0x52c0_d4e8_f317,
+));
+
+/// Read a `u32` variable, `None` if it doesn't exist
+pub fn read_u32(rt: &RuntimeServices, name: &str) -> Option<u32> {
+    let mut name_buf = [0u16; 64];
+    let name = CStr16::from_str_with_buf(name, &mut name_buf)
+        .expect("failed to convert variable name to ucs-2");
+    let mut buf = [0u8; 4];
+    if rt.get_variable(name, &RBOOT_VENDOR, &mut buf).is_ok() {
+        Some(u32::from_le_bytes(buf))
+    } else {
+        None
+    }
+}
+
+/// Write a non-volatile `u32` variable, which fails e.g. if the variable store
+/// is full or write protected
+pub fn write_u32(rt: &RuntimeServices, name: &str, value: u32) -> uefi::Result {
+    let mut name_buf = [0u16; 64];
+    let name = CStr16::from_str_with_buf(name, &mut name_buf)
+        .expect("failed to convert variable name to ucs-2");
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    rt.set_variable(name, &RBOOT_VENDOR, attributes, &value.to_le_bytes())
+}